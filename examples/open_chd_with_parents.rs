@@ -18,7 +18,7 @@ fn main() {
     }
 
     let main_chd_path = PathBuf::from(env::args().nth(1).unwrap());
-    let possible_parent_paths: Vec<PathBuf> = env::args().skip(2).map(|x| PathBuf::from(x)).collect();
+    let possible_parent_paths: Vec<PathBuf> = env::args().skip(2).map(PathBuf::from).collect();
 
     let chd = ChdImage::open_with_parent(main_chd_path, &possible_parent_paths);
 
@@ -35,7 +35,7 @@ fn main() {
                 for x in sha1.iter() {
                     print!("{:02x}", x);
                 }
-                println!("");
+                println!();
             }
         } else if let Err(e) = sha1s {
             println!("Error generating track SHA1s: {:?}", e);
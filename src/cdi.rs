@@ -0,0 +1,409 @@
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use log::debug;
+
+use thiserror::Error;
+
+use vec_map::VecMap;
+
+use crate::disc::{Disc, DiscTrack, SubchannelData, SubchannelLayout, TrackData};
+use crate::sector::SectorFormat;
+use crate::{OpenOptions, TrackFlags, TrackType};
+
+
+const CDI_V2: u32 = 0x8000_0004;
+const CDI_V3: u32 = 0x8000_0005;
+const CDI_V35: u32 = 0x8000_0006;
+
+const TRACK_START_MARK: [u8; 10] = [0, 0, 0x01, 0, 0, 0, 0xff, 0xff, 0xff, 0xff];
+
+#[derive(Debug, Error)]
+pub enum CdiError {
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    SidecarError(#[from] crate::SidecarError),
+    #[error("Input file does not seem like a CDI file (no valid trailer found)")]
+    NotACdiFile,
+    #[error("CDI descriptor is truncated or invalid")]
+    Truncated,
+    #[error("Invalid track start mark in CDI descriptor")]
+    InvalidTrackStartMark,
+    #[error("Unsupported track mode {0} in CDI descriptor")]
+    UnsupportedTrackMode(u32),
+    #[error("Unsupported sector size value {0} in CDI descriptor")]
+    UnsupportedSectorSize(u32),
+    #[error("Invalid track layout in CDI descriptor")]
+    InvalidTrackLayout,
+    #[error("No tracks in CDI image")]
+    NoTracks,
+}
+
+// Reads the trailer at the end of `file` and returns the version together
+// with the absolute offset of the descriptor
+fn read_trailer<F>(file: &mut F) -> std::io::Result<Option<(u32, u64)>>
+    where F: Read + Seek
+{
+    let len = file.seek(SeekFrom::End(0))?;
+    if len < 8 {
+        return Ok(None);
+    }
+    let mut trailer = [0u8; 8];
+    file.seek(SeekFrom::Start(len - 8))?;
+    file.read_exact(&mut trailer)?;
+    let version = u32::from_le_bytes(trailer[0..4].try_into().unwrap());
+    let offset = u32::from_le_bytes(trailer[4..8].try_into().unwrap()) as u64;
+    let descriptor_offset = match version {
+        CDI_V2 | CDI_V3 => offset,
+        // Version 3.5 stores the distance from the end of the file instead
+        CDI_V35 if offset <= len => len - offset,
+        _ => return Ok(None),
+    };
+    if offset == 0 || descriptor_offset >= len {
+        return Ok(None);
+    }
+    Ok(Some((version, descriptor_offset)))
+}
+
+pub(crate) fn has_cdi_trailer(file: &mut File) -> std::io::Result<bool> {
+    Ok(read_trailer(file)?.is_some())
+}
+
+// Cursor over the descriptor, which consists of a lot of fields of unknown
+// purpose that need to be skipped.
+struct Descriptor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Descriptor<'a> {
+    fn skip(&mut self, n: usize) -> Result<(), CdiError> {
+        if self.pos + n > self.data.len() {
+            return Err(CdiError::Truncated);
+        }
+        self.pos += n;
+        Ok(())
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], CdiError> {
+        let bytes = self.data.get(self.pos..self.pos + n).ok_or(CdiError::Truncated)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, CdiError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, CdiError> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, CdiError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+}
+
+#[derive(Debug)]
+struct CdiTrack {
+    pregap: u32,
+    length: u32,
+    track_type: TrackType,
+    // Absolute LBA of the first sector of the track's pregap
+    start_lba: u32,
+    total_length: u32,
+    sector_size: u32,
+}
+
+fn parse_track(desc: &mut Descriptor, version: u32) -> Result<CdiTrack, CdiError> {
+    // Extra data written by DiscJuggler 3.00.780 and up
+    if desc.u32()? != 0 {
+        desc.skip(8)?;
+    }
+    for _ in 0..2 {
+        if desc.bytes(10)? != TRACK_START_MARK {
+            return Err(CdiError::InvalidTrackStartMark);
+        }
+    }
+    desc.skip(4)?;
+    let filename_len = desc.u8()? as usize;
+    desc.skip(filename_len)?;
+    desc.skip(11 + 4 + 4)?;
+    // Extra data written by DiscJuggler 4
+    if desc.u32()? == 0x8000_0000 {
+        desc.skip(8)?;
+    }
+    desc.skip(2)?;
+    let pregap = desc.u32()?;
+    let length = desc.u32()?;
+    desc.skip(6)?;
+    let mode = desc.u32()?;
+    desc.skip(12)?;
+    let start_lba = desc.u32()?;
+    let total_length = desc.u32()?;
+    desc.skip(16)?;
+    let sector_size = match desc.u32()? {
+        0 => 2048,
+        1 => 2336,
+        2 => 2352,
+        4 => 2448,
+        x => return Err(CdiError::UnsupportedSectorSize(x)),
+    };
+    let track_type = match mode {
+        0 => TrackType::Audio,
+        1 => TrackType::Mode1,
+        2 => TrackType::Mode2,
+        x => return Err(CdiError::UnsupportedTrackMode(x)),
+    };
+    desc.skip(29)?;
+    if version != CDI_V2 {
+        desc.skip(5)?;
+        // Extra data written by DiscJuggler 3.00.780 and up
+        if desc.u32()? == 0xffff_ffff {
+            desc.skip(78)?;
+        }
+    }
+
+    Ok(CdiTrack {
+        pregap,
+        length,
+        track_type,
+        start_lba,
+        total_length,
+        sector_size,
+    })
+}
+
+/// An image in DiscJuggler format (`.cdi`), versions 2, 3 and 3.5 are
+/// supported.
+pub struct CdiImage {
+    disc: Disc,
+}
+
+impl CdiImage {
+    pub fn open<P>(path: P) -> Result<CdiImage, CdiError>
+        where P: AsRef<Path>
+    {
+        Self::_open(path.as_ref(), &OpenOptions::new())
+    }
+
+    /// Like `open`, but loads the side-car files according to `options`.
+    pub fn open_with_options<P>(path: P, options: &OpenOptions) -> Result<CdiImage, CdiError>
+        where P: AsRef<Path>
+    {
+        Self::_open(path.as_ref(), options)
+    }
+
+    fn _open(path: &Path, options: &OpenOptions) -> Result<CdiImage, CdiError> {
+        let mut file = File::open(path)?;
+        let (version, descriptor_offset) = read_trailer(&mut file)?.ok_or(CdiError::NotACdiFile)?;
+        let len = file.seek(SeekFrom::End(0))?;
+        let mut descriptor = Vec::new();
+        file.seek(SeekFrom::Start(descriptor_offset))?;
+        file.by_ref().take(len - descriptor_offset).read_to_end(&mut descriptor)?;
+        let mut desc = Descriptor { data: &descriptor, pos: 0 };
+
+        let mut cdi_tracks = Vec::new();
+        let num_sessions = desc.u16()?;
+        for session in 0..num_sessions {
+            let num_tracks = desc.u16()?;
+            for _ in 0..num_tracks {
+                let track = parse_track(&mut desc, version)?;
+                debug!("CDI session {} track {}: {:?}", session + 1, cdi_tracks.len() + 1, track);
+                cdi_tracks.push((session as u8 + 1, track));
+            }
+            // Session trailer
+            desc.skip(4 + 8)?;
+            if version != CDI_V2 {
+                desc.skip(1)?;
+            }
+        }
+        if cdi_tracks.is_empty() {
+            return Err(CdiError::NoTracks);
+        }
+
+        // Tracks are stored one after another including their pregaps
+        let mut offset = 0;
+        let mut tracks: Vec<DiscTrack> = Vec::new();
+        for (i, (session, track)) in cdi_tracks.iter().enumerate() {
+            let format = match (track.sector_size, track.track_type) {
+                (2352, _) | (2448, _) => SectorFormat::Raw,
+                (2048, TrackType::Mode1) => SectorFormat::Mode1Cooked,
+                (2048, TrackType::Mode2) => SectorFormat::Mode2Form1Cooked,
+                (2336, TrackType::Mode2) => SectorFormat::Mode2Cooked,
+                _ => return Err(CdiError::UnsupportedSectorSize(track.sector_size)),
+            };
+            let index_one = track.start_lba + track.pregap;
+            let start = if i == 0 { 0 } else { track.start_lba };
+            let mut indices = VecMap::new();
+            if start < index_one {
+                indices.insert(0, start);
+            }
+            indices.insert(1, index_one);
+
+            if let Some(previous) = tracks.last_mut() {
+                if start <= previous.start {
+                    return Err(CdiError::InvalidTrackLayout);
+                }
+                previous.num_sectors = if previous.session == *session {
+                    start - previous.start
+                } else {
+                    // The last track of a session ends with its data, the
+                    // lead-out and lead-in up to the next session aren't
+                    // part of the image.
+                    let data_end = previous.data.start + previous.data.num_sectors;
+                    if data_end > start {
+                        return Err(CdiError::InvalidTrackLayout);
+                    }
+                    data_end - previous.start
+                };
+            }
+
+            tracks.push(DiscTrack {
+                track_type: track.track_type,
+                session: *session,
+                flags: TrackFlags::empty(),
+                start,
+                num_sectors: 0,
+                indices,
+                data: TrackData {
+                    file: 0,
+                    start: track.start_lba,
+                    num_sectors: track.pregap + track.length,
+                    offset,
+                    sector_size: track.sector_size,
+                    format,
+                    subchannel: if track.sector_size == 2448 {
+                        Some(SubchannelData {
+                            file: 0,
+                            offset: offset + 2352,
+                            stride: 2448,
+                            layout: SubchannelLayout::Interleaved,
+                        })
+                    } else {
+                        None
+                    },
+                },
+            });
+            offset += track.total_length as u64 * track.sector_size as u64;
+        }
+        if offset > descriptor_offset {
+            return Err(CdiError::InvalidTrackLayout);
+        }
+        let last_track = tracks.last_mut().unwrap();
+        last_track.num_sectors = last_track.data.start + last_track.data.num_sectors - last_track.start;
+
+        let sidecars = options.load_sidecars(path)?;
+
+        Ok(CdiImage {
+            disc: Disc::new(vec![file], tracks, sidecars),
+        })
+    }
+
+    /// Returns whether the image contains subchannel data for all tracks
+    /// (i.e. uses 2448 byte sectors throughout).
+    pub fn has_subchannel_data(&self) -> bool {
+        self.disc.has_subchannel_data()
+    }
+}
+
+crate::disc::forward_image_to_disc!(CdiImage);
+
+#[cfg(test)]
+mod tests {
+    use crate::cdi::*;
+    use crate::{Event, Image, MsfIndex};
+
+    fn track_descriptor(pregap: u32, length: u32, mode: u32, start_lba: u32, sector_size_value: u32) -> Vec<u8> {
+        let mut desc = 0u32.to_le_bytes().to_vec();
+        desc.extend(TRACK_START_MARK);
+        desc.extend(TRACK_START_MARK);
+        desc.extend([0u8; 4]);
+        desc.push(4);
+        desc.extend(b"test");
+        desc.extend([0u8; 11 + 4 + 4]);
+        desc.extend(0u32.to_le_bytes());
+        desc.extend([0u8; 2]);
+        desc.extend(pregap.to_le_bytes());
+        desc.extend(length.to_le_bytes());
+        desc.extend([0u8; 6]);
+        desc.extend(mode.to_le_bytes());
+        desc.extend([0u8; 12]);
+        desc.extend(start_lba.to_le_bytes());
+        desc.extend((pregap + length).to_le_bytes());
+        desc.extend([0u8; 16]);
+        desc.extend(sector_size_value.to_le_bytes());
+        desc.extend([0u8; 29]);
+        desc.extend([0u8; 5]);
+        desc.extend(0u32.to_le_bytes());
+        desc
+    }
+
+    // A v3.5 image with two sessions: an audio track with 2 sectors of data in
+    // the first and a cooked Mode 2 track with 3 sectors in the second
+    // session, both with a pregap of 150 sectors.
+    fn build_cdi() -> Vec<u8> {
+        let mut cdi = Vec::new();
+        for i in 0..152u32 {
+            cdi.extend([i as u8; 2352]);
+        }
+        for i in 0..153u32 {
+            cdi.extend([i as u8; 2336]);
+        }
+        let descriptor_offset = cdi.len();
+
+        cdi.extend(2u16.to_le_bytes());
+        cdi.extend(1u16.to_le_bytes());
+        cdi.extend(track_descriptor(150, 2, 0, 0, 2));
+        cdi.extend([0u8; 13]);
+        cdi.extend(1u16.to_le_bytes());
+        cdi.extend(track_descriptor(150, 3, 2, 11552, 1));
+        cdi.extend([0u8; 13]);
+
+        let trailer_len = (cdi.len() - descriptor_offset + 8) as u32;
+        cdi.extend(CDI_V35.to_le_bytes());
+        cdi.extend(trailer_len.to_le_bytes());
+        cdi
+    }
+
+    #[test]
+    fn cdi_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.cdi");
+        std::fs::write(&path, build_cdi()).unwrap();
+
+        let mut image = CdiImage::open(&path).unwrap();
+        assert_eq!(image.num_tracks(), 2);
+        assert_eq!(image.track_start(1).unwrap(), MsfIndex::from_lba(150).unwrap());
+        assert_eq!(image.track_start(2).unwrap(), MsfIndex::from_lba(11702).unwrap());
+        assert_eq!(image.lead_out().unwrap(), MsfIndex::from_lba(11705).unwrap());
+
+        let toc = image.toc().unwrap();
+        assert_eq!(toc.sessions.len(), 2);
+        assert_eq!(toc.sessions[0].lead_out, MsfIndex::from_lba(152).unwrap());
+        assert_eq!(toc.track(1).unwrap().length, 2);
+        assert_eq!((toc.sessions[1].first_track, toc.sessions[1].last_track), (2, 2));
+        let track = toc.track(2).unwrap();
+        assert_eq!((track.session, track.pregap, track.length, track.control), (2, 150, 3, 0x04));
+
+        let mut buf = [0u8; 2352];
+        image.copy_current_sector(&mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 150));
+
+        // Between the sessions
+        assert!(image.set_location(MsfIndex::from_lba(152).unwrap()).is_err());
+
+        image.set_location(MsfIndex::from_lba(151).unwrap()).unwrap();
+        assert_eq!(image.advance_position().unwrap(), Some(Event::TrackChange));
+        assert_eq!(image.current_global_msf().unwrap(), MsfIndex::from_lba(11552).unwrap());
+        assert_eq!(image.current_index().unwrap(), 0);
+
+        image.set_location_to_track(2).unwrap();
+        image.copy_current_sector(&mut buf).unwrap();
+        assert_eq!(&buf[12..16], &[0x02, 0x36, 0x02, 0x02]);
+        assert!(buf[16..].iter().all(|&x| x == 150));
+    }
+}
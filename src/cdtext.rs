@@ -0,0 +1,331 @@
+// Parsing of binary CD-TEXT data as stored in the lead-in of a disc and in
+// `.cdt` files: a sequence of 18 byte packs, each holding 12 bytes of text
+// or block information.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use crate::subchannel;
+use crate::{debug, warn};
+
+use thiserror::Error;
+
+
+const PACK_LEN: usize = 18;
+const PACK_SIZE_INFO: u8 = 0x8f;
+
+#[derive(Debug, Error)]
+pub enum CdTextError {
+    #[error("I/O error")]
+    IoError(#[from] std::io::Error),
+    #[error("Length of CD-TEXT data is not a multiple of the pack size")]
+    InvalidLength,
+}
+
+/// Character set of the texts of a CD-TEXT block
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CharacterCode {
+    Iso8859_1,
+    Ascii,
+    /// Shift-JIS (double byte)
+    MsJis,
+    /// Korean (double byte)
+    Korean,
+    /// Mandarin Chinese (double byte)
+    Mandarin,
+    Other(u8),
+}
+
+impl CharacterCode {
+    fn from_code(code: u8) -> CharacterCode {
+        match code {
+            0x00 => CharacterCode::Iso8859_1,
+            0x01 => CharacterCode::Ascii,
+            0x80 => CharacterCode::MsJis,
+            0x81 => CharacterCode::Korean,
+            0x82 => CharacterCode::Mandarin,
+            _ => CharacterCode::Other(code),
+        }
+    }
+
+    pub fn is_double_byte(self) -> bool {
+        matches!(self, CharacterCode::MsJis | CharacterCode::Korean | CharacterCode::Mandarin)
+    }
+}
+
+/// Kinds of text stored for the album and each track
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TextKind {
+    Title,
+    Performer,
+    Songwriter,
+    Composer,
+    Arranger,
+    Message,
+    /// Disc identification, only given for the album
+    DiscId,
+    /// UPC/EAN of the album or ISRC of a track
+    UpcIsrc,
+}
+
+impl TextKind {
+    fn from_pack_type(pack_type: u8) -> Option<TextKind> {
+        match pack_type {
+            0x80 => Some(TextKind::Title),
+            0x81 => Some(TextKind::Performer),
+            0x82 => Some(TextKind::Songwriter),
+            0x83 => Some(TextKind::Composer),
+            0x84 => Some(TextKind::Arranger),
+            0x85 => Some(TextKind::Message),
+            0x86 => Some(TextKind::DiscId),
+            0x8e => Some(TextKind::UpcIsrc),
+            _ => None,
+        }
+    }
+}
+
+// Raw text by track number (0 for the album) and kind
+type Texts = BTreeMap<(u8, TextKind), Vec<u8>>;
+
+/// The texts of one language.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CdTextBlock {
+    /// Language code as defined by the European Broadcasting Union (0x09:
+    /// English)
+    pub language: u8,
+    pub character_code: CharacterCode,
+    pub first_track: u8,
+    pub last_track: u8,
+    texts: Texts,
+}
+
+impl CdTextBlock {
+    /// Returns the text of the given kind for `track` (0 for the album) as
+    /// stored, i.e. in the block's character set.
+    pub fn raw_text(&self, track: u8, kind: TextKind) -> Option<&[u8]> {
+        self.texts.get(&(track, kind)).map(|x| &x[..])
+    }
+
+    /// Returns the text of the given kind for `track` (0 for the album).
+    /// Texts in double byte character sets can't be decoded and are only
+    /// available through `raw_text`.
+    pub fn text(&self, track: u8, kind: TextKind) -> Option<String> {
+        match self.character_code {
+            CharacterCode::Iso8859_1 | CharacterCode::Ascii => {
+                // ISO 8859-1 maps directly to the first 256 code points
+                self.raw_text(track, kind).map(|x| x.iter().map(|&c| c as char).collect())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// CD-TEXT data of a disc, consisting of up to 8 blocks in different
+/// languages.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CdText {
+    pub blocks: Vec<CdTextBlock>,
+}
+
+impl CdText {
+    /// Parses CD-TEXT packs, optionally preceded by the 4 byte header
+    /// returned by the READ TOC command. Packs with an invalid CRC are
+    /// skipped.
+    pub fn parse(data: &[u8]) -> Result<CdText, CdTextError> {
+        let mut data = data;
+        if data.len() % PACK_LEN == 4 || data.len() % PACK_LEN == 5 {
+            data = &data[4..];
+        }
+        // Some tools add a terminating null byte
+        if data.len() % PACK_LEN == 1 && data.last() == Some(&0) {
+            data = &data[..data.len() - 1];
+        }
+        if !data.len().is_multiple_of(PACK_LEN) {
+            return Err(CdTextError::InvalidLength);
+        }
+
+        // Block number -> (double byte, texts by track and kind)
+        let mut block_texts: BTreeMap<u8, (bool, Texts)> = BTreeMap::new();
+        // Block number -> contents of the three block size information packs
+        let mut size_info: BTreeMap<u8, [u8; 36]> = BTreeMap::new();
+        for pack in data.chunks_exact(PACK_LEN) {
+            let crc = u16::from_be_bytes([pack[16], pack[17]]);
+            // Not all tools fill in the CRC
+            if crc != 0 && crc != subchannel::q_crc(&pack[..16]) {
+                warn!("Skipping CD-TEXT pack {} with invalid CRC", pack[2]);
+                continue;
+            }
+            let (pack_type, track, block_info) = (pack[0], pack[1], pack[3]);
+            let block = (block_info >> 4) & 0x07;
+            let double_byte = block_info & 0x80 != 0;
+            // Position within its string of the first character of the pack
+            let char_pos = block_info & 0x0f;
+            let text = &pack[4..16];
+            // Extension packs carry no text of their own
+            if track & 0x80 != 0 {
+                continue;
+            }
+            if pack_type == PACK_SIZE_INFO {
+                if track < 3 {
+                    let info = size_info.entry(block).or_insert([0; 36]);
+                    info[track as usize * 12..(track as usize + 1) * 12].copy_from_slice(text);
+                }
+            } else if let Some(kind) = TextKind::from_pack_type(pack_type) {
+                let (_, texts) = block_texts.entry(block).or_insert((double_byte, BTreeMap::new()));
+                // The pack continues the string of `track` and holds the
+                // null-terminated strings of the following tracks
+                let mut track = track;
+                let mut new_string = char_pos == 0;
+                for c in text.chunks_exact(if double_byte { 2 } else { 1 }) {
+                    if c.iter().all(|&x| x == 0) {
+                        track += 1;
+                        new_string = true;
+                    } else if track <= 99 {
+                        let string = texts.entry((track, kind)).or_default();
+                        if new_string {
+                            string.clear();
+                            new_string = false;
+                        }
+                        string.extend_from_slice(c);
+                    }
+                }
+            } else {
+                debug!("Ignoring CD-TEXT pack of type {:#x}", pack_type);
+            }
+        }
+
+        let mut blocks = Vec::new();
+        for (block, (double_byte, mut texts)) in block_texts {
+            let info = size_info.get(&block);
+            let character_code = match info {
+                Some(info) => CharacterCode::from_code(info[0]),
+                None if double_byte => CharacterCode::MsJis,
+                None => CharacterCode::Iso8859_1,
+            };
+            // A tab means the same text as for the previous track
+            let keys: Vec<_> = texts.keys().copied().collect();
+            for (track, kind) in keys {
+                if texts[&(track, kind)] == b"\t" || texts[&(track, kind)] == b"\t\t" {
+                    let previous = track.checked_sub(1).and_then(|x| texts.get(&(x, kind))).cloned();
+                    texts.insert((track, kind), previous.unwrap_or_default());
+                }
+            }
+            let max_track = texts.keys().map(|&(track, _)| track).max().unwrap_or(0);
+            blocks.push(CdTextBlock {
+                language: info.map_or(0, |x| x[28 + block as usize]),
+                character_code,
+                first_track: info.map_or(1, |x| x[1]),
+                last_track: info.map_or(max_track, |x| x[2]),
+                texts,
+            });
+        }
+
+        Ok(CdText { blocks })
+    }
+
+    /// Loads the CD-TEXT data from a `.cdt` file.
+    pub fn load<P>(path: P) -> Result<CdText, CdTextError>
+        where P: AsRef<Path>
+    {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        CdText::parse(&data)
+    }
+
+    /// Returns the text of the given kind for `track` (0 for the album) from
+    /// the first block it can be decoded from.
+    pub fn text(&self, track: u8, kind: TextKind) -> Option<String> {
+        self.blocks.iter().find_map(|x| x.text(track, kind))
+    }
+
+    pub fn album_title(&self) -> Option<String> {
+        self.text(0, TextKind::Title)
+    }
+
+    pub fn album_performer(&self) -> Option<String> {
+        self.text(0, TextKind::Performer)
+    }
+
+    pub fn track_title(&self, track: u8) -> Option<String> {
+        self.text(track, TextKind::Title)
+    }
+
+    pub fn track_performer(&self, track: u8) -> Option<String> {
+        self.text(track, TextKind::Performer)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // Builds the packs of the given type holding the null-terminated single
+    // byte strings in `text`, starting at `track`
+    fn text_packs(pack_type: u8, track: u8, block_info: u8, text: &[u8], seq: &mut u8) -> Vec<u8> {
+        let mut packs = Vec::new();
+        let (mut track, mut char_pos) = (track, 0u8);
+        for chunk in text.chunks(12) {
+            let mut pack = [0u8; PACK_LEN];
+            pack[..4].copy_from_slice(&[pack_type, track, *seq, block_info | char_pos.min(15)]);
+            for &c in chunk {
+                if c == 0 {
+                    track += 1;
+                    char_pos = 0;
+                } else {
+                    char_pos += 1;
+                }
+            }
+            pack[4..4 + chunk.len()].copy_from_slice(chunk);
+            let crc = subchannel::q_crc(&pack[..16]);
+            pack[16..].copy_from_slice(&crc.to_be_bytes());
+            packs.extend_from_slice(&pack);
+            *seq += 1;
+        }
+        packs
+    }
+
+    // CD-TEXT with an English block for an album with two tracks and a
+    // second block in Shift-JIS
+    pub(crate) fn build_cd_text() -> Vec<u8> {
+        let mut seq = 0;
+        let mut data = vec![0, 0, 0, 0];
+        data.extend(text_packs(0x80, 0, 0x00, b"Album\0Track One\0Track Two\0", &mut seq));
+        data.extend(text_packs(0x81, 0, 0x00, b"Artist\0\t\0Guest\0\0\0\0\0\0\0\0", &mut seq));
+        let mut info = [0u8; 36];
+        info[..3].copy_from_slice(&[0x00, 1, 2]);
+        info[28] = 0x09;
+        for i in 0..3 {
+            data.extend(text_packs(0x8f, i, 0x00, &info[i as usize * 12..(i as usize + 1) * 12], &mut seq));
+        }
+        let mut seq = 0;
+        data.extend(text_packs(0x80, 0, 0x90, &[0x83, 0x41, 0, 0, 0x83, 0x43, 0, 0, 0, 0, 0, 0], &mut seq));
+        data
+    }
+
+    #[test]
+    fn cd_text_packs() {
+        let mut data = build_cd_text();
+        let cd_text = CdText::parse(&data).unwrap();
+        assert_eq!(cd_text.blocks.len(), 2);
+        let block = &cd_text.blocks[0];
+        assert_eq!((block.language, block.character_code, block.first_track, block.last_track),
+                   (0x09, CharacterCode::Iso8859_1, 1, 2));
+        assert_eq!(cd_text.album_title().as_deref(), Some("Album"));
+        assert_eq!(cd_text.track_title(2).as_deref(), Some("Track Two"));
+        assert_eq!(cd_text.album_performer().as_deref(), Some("Artist"));
+        assert_eq!(cd_text.track_performer(1).as_deref(), Some("Artist"));
+        assert_eq!(cd_text.track_performer(2).as_deref(), Some("Guest"));
+
+        let block = &cd_text.blocks[1];
+        assert_eq!(block.character_code, CharacterCode::MsJis);
+        assert_eq!(block.raw_text(1, TextKind::Title), Some(&[0x83, 0x43][..]));
+        assert_eq!(block.text(1, TextKind::Title), None);
+
+        // Packs with a broken CRC are skipped
+        data[4 + 17] ^= 0xff;
+        assert_eq!(CdText::parse(&data).unwrap().album_title(), None);
+        assert!(matches!(CdText::parse(&data[..20]), Err(CdTextError::InvalidLength)));
+    }
+}
@@ -3,7 +3,6 @@ mod chd_thread;
 
 mod track_metadata;
 
-use std::collections::BTreeSet;
 use std::convert::TryInto;
 use std::path::Path;
 use std::sync::mpsc::RecvError;
@@ -12,11 +11,17 @@ use chd_rs::Chd;
 use chd_rs::metadata::Metadata;
 use chd_rs::header::Header;
 
-use log::{debug, info, trace, warn, error};
+use log::{debug, trace, warn};
 
 use thiserror::Error;
 
-use crate::{Event, Image, ImageError, MsfIndex, TrackType};
+use crate::cdtext::CdText;
+use crate::disc::SubchannelLayout;
+use crate::options::Sidecars;
+use crate::sbi::SbiRecords;
+use crate::subchannel;
+use crate::toc::{Toc, TrackFlags, TrackInfo};
+use crate::{Event, Image, ImageError, MsfIndex, OpenOptions, ReadSeek, Sidecar, TrackType};
 use track_metadata::CdTrackInfo;
 
 const BYTES_PER_SECTOR: u32 =  2352 + 96;
@@ -37,12 +42,31 @@ struct Track {
     track_info: CdTrackInfo,
 }
 
+impl Track {
+    // CHDs only know about the pregap (index 00) and index 01 of a track.
+    // Tracks described by the old metadata format don't have a pregap, like
+    // chdman assumes when extracting them.
+    fn index_one_lba(&self) -> u32 {
+        self.start_lba + self.track_info.pregap.unwrap_or(0)
+    }
+
+    fn index_for_lba(&self, lba: u32) -> u8 {
+        if lba >= self.index_one_lba() {
+            1
+        } else {
+            0
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum ChdImageError {
     #[error(transparent)]
     ChdError(#[from] chd_rs::Error),
     #[error(transparent)]
     IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    SidecarError(#[from] crate::SidecarError),
     #[error("Error while parsing track metadata: {0}")]
     TrackParseError(#[from] text_io::Error),
     #[error("CHD file does not seem like a CDROM image (wrong hunk size)")]
@@ -68,7 +92,7 @@ pub struct ChdImage {
     hunk_reader: chd_thread::ChdHunkReader,
 
     #[cfg(not(feature = "multithreading"))]
-    chd: Chd<std::fs::File>,
+    chd: Chd<Box<dyn ReadSeek>>,
     tracks: Vec<Track>,
 
     // Intermediate buffer for the compressed data, needed for chd crate
@@ -76,30 +100,52 @@ pub struct ChdImage {
     comp_buf: Vec<u8>,
     hunk: Vec<u8>,
     current_hunk_no: Option<u32>,
+    // Hunk read by the last random access that didn't hit `hunk`
+    random_hunk: Vec<u8>,
+    random_hunk_no: Option<u32>,
     current_lba: u32,
     // Starts counting from 0
     current_track: usize,
 
     num_hunks: u32,
-    hunk_len: u32,
     sectors_per_hunk: u32,
 
-    invalid_subq_lbas: Option<BTreeSet<u32>>,
+    sidecars: Sidecars,
 }
 
 impl ChdImage {
     pub fn open<P>(path: P) -> Result<ChdImage, ChdImageError>
         where P: AsRef<Path>
     {
-        ChdImage::_open(path.as_ref())
+        ChdImage::_open(path.as_ref(), &OpenOptions::new())
     }
 
-    fn _open(path: &Path) -> Result<ChdImage, ChdImageError> {
+    /// Like `open`, but loads the side-car files according to `options`.
+    pub fn open_with_options<P>(path: P, options: &OpenOptions) -> Result<ChdImage, ChdImageError>
+        where P: AsRef<Path>
+    {
+        ChdImage::_open(path.as_ref(), options)
+    }
+
+    fn _open(path: &Path, options: &OpenOptions) -> Result<ChdImage, ChdImageError> {
         let chd = Chd::open(
-            std::fs::File::open(path)?,
+            Self::open_source(path)?,
             None
         )?;
-        Self::from_chd(chd, path)
+        Self::from_chd(chd, options.load_sidecars(path)?)
+    }
+
+    /// Opens a CHD without a parent from an arbitrary source, e.g. an
+    /// in-memory buffer. No SBI file is loaded in this case.
+    pub fn from_reader<R>(reader: R) -> Result<ChdImage, ChdImageError>
+        where R: ReadSeek + 'static
+    {
+        let chd = Chd::open(Box::new(reader) as Box<dyn ReadSeek>, None)?;
+        Self::from_chd(chd, Sidecars::default())
+    }
+
+    fn open_source(path: &Path) -> Result<Box<dyn ReadSeek>, ChdImageError> {
+        Ok(Box::new(std::fs::File::open(path)?))
     }
 
     /// Opens the CHD file referred to by `path` while opening parents recursively
@@ -111,30 +157,41 @@ impl ChdImage {
     /// version mismatches with the child CHD.
     pub fn open_with_parent<P, PP>(path: P, possible_parents: &[PP]) -> Result<ChdImage, ChdImageError>
         where P: AsRef<Path>, PP: AsRef<Path>
+    {
+        Self::open_with_parent_and_options(path, possible_parents, &OpenOptions::new())
+    }
+
+    /// Like `open_with_parent`, but loads the side-car files according to
+    /// `options`.
+    pub fn open_with_parent_and_options<P, PP>(path: P, possible_parents: &[PP], options: &OpenOptions)
+        -> Result<ChdImage, ChdImageError>
+        where P: AsRef<Path>, PP: AsRef<Path>
     {
         let possible_parents: Vec<&Path> = possible_parents.iter().map(|x| x.as_ref()).collect();
-        Self::_open_with_parent(path.as_ref(), &possible_parents)
+        Self::_open_with_parent(path.as_ref(), &possible_parents, options)
     }
 
-    fn _open_with_parent(path: &Path, possible_parents: &[&Path]) -> Result<ChdImage, ChdImageError> {
-        let file = std::fs::File::open(path)?;
+    fn _open_with_parent(path: &Path, possible_parents: &[&Path], options: &OpenOptions)
+        -> Result<ChdImage, ChdImageError>
+    {
+        let file = Self::open_source(path)?;
         let chd = Chd::open(file, None)?;
 
         if !chd.header().has_parent() {
             debug!("open_with_parent: Opening CHD without a parent as it doesn't require one");
-            Self::from_chd(chd, path)
+            Self::from_chd(chd, options.load_sidecars(path)?)
         } else {
             let chd = Self::open_with_parents_recursively(path, possible_parents, 0)?;
-            Self::from_chd(*chd, path)
+            Self::from_chd(*chd, options.load_sidecars(path)?)
         }
     }
 
-    fn open_with_parents_recursively(path: &Path, possible_parents: &[&Path], depth: u8) -> Result<Box<Chd<std::fs::File>>, ChdImageError> {
+    fn open_with_parents_recursively(path: &Path, possible_parents: &[&Path], depth: u8) -> Result<Box<Chd<Box<dyn ReadSeek>>>, ChdImageError> {
         if depth >= 10 {
             return Err(ChdImageError::RecursionDepthExceeded);
         }
 
-        let mut file = std::fs::File::open(path)?;
+        let mut file = Self::open_source(path)?;
         let child_header = Header::try_read_header(&mut file)?;
 
         if !child_header.has_parent() {
@@ -150,7 +207,7 @@ impl ChdImage {
             };
 
             for p in possible_parents {
-                let sha1 = match Self::chd_header_sha1(p.as_ref()) {
+                let sha1 = match Self::chd_header_sha1(p) {
                     Ok(Some(sha1)) => sha1,
                     Ok(None) => {
                         warn!("Skipped possible parent CHD {:?} because \
@@ -166,7 +223,7 @@ impl ChdImage {
 
                 if sha1 == parent_sha1 {
                     debug!("Opening child {:?} with parent {:?}", path, p);
-                    let parent = Self::open_with_parents_recursively(p.as_ref(), possible_parents, depth + 1)?;
+                    let parent = Self::open_with_parents_recursively(p, possible_parents, depth + 1)?;
                     return Ok(Box::new(Chd::open(
                         file,
                         Some(parent)
@@ -184,12 +241,12 @@ impl ChdImage {
         Ok(chd_header.sha1())
     }
 
-    fn from_chd(mut chd: Chd<std::fs::File>, path: &Path) -> Result<ChdImage, ChdImageError> {
+    fn from_chd(mut chd: Chd<Box<dyn ReadSeek>>, sidecars: Sidecars) -> Result<ChdImage, ChdImageError> {
         let num_hunks = chd.header().hunk_count();
         let hunk_len = chd.header().hunk_size();
         let sectors_per_hunk = hunk_len / BYTES_PER_SECTOR;
 
-        if hunk_len % BYTES_PER_SECTOR != 0 {
+        if !hunk_len.is_multiple_of(BYTES_PER_SECTOR) {
             return Err(ChdImageError::WrongHunkSize);
         }
 
@@ -229,18 +286,6 @@ impl ChdImage {
             });
         }
 
-        let sbi_path = path.with_extension("sbi");
-        let mut invalid_subq_lbas = None;
-        if sbi_path.exists() {
-            match crate::sbi::load_sbi_file(sbi_path) {
-                Ok(set) => {
-                    info!("Found and loaded SBI file");
-                    invalid_subq_lbas = Some(set);
-                }
-                Err(e) => warn!("Failed to load SBI file: {}", e),
-            }
-        }
-
         Ok(ChdImage {
             #[cfg(feature = "multithreading")]
             hunk_reader: chd_thread::ChdHunkReader::new(chd),
@@ -249,41 +294,114 @@ impl ChdImage {
 
             #[cfg(not(feature = "multithreading"))]
             comp_buf,
+            random_hunk: hunk.clone(),
+            random_hunk_no: Some(0),
             hunk,
             current_hunk_no: Some(0),
             current_lba: 150,
             current_track: 0,
 
             num_hunks,
-            hunk_len,
             sectors_per_hunk,
 
             tracks,
 
-            invalid_subq_lbas,
+            sidecars,
         })
     }
 
+    fn track_for_lba(&self, lba: u32) -> Option<usize> {
+        let contains = |x: &Track| lba >= x.start_lba && lba < (x.start_lba + x.track_info.frames);
+        if contains(&self.tracks[self.current_track]) {
+            Some(self.current_track)
+        } else {
+            self.tracks.iter().position(contains)
+        }
+    }
+
+    // Absolute LBA of the first sector belonging to a track. The first track
+    // additionally covers the two seconds before it.
+    fn track_first_lba(&self, track: usize) -> u32 {
+        if track == 0 {
+            0
+        } else {
+            self.tracks[track].start_lba
+        }
+    }
+
+    // The padding sectors at the end of the last track don't count
+    fn lead_out_lba(&self) -> u32 {
+        let last_track = self.tracks.last().unwrap();
+        last_track.start_lba + last_track.track_info.frames
+    }
+
     fn update_current_track(&mut self, lba: u32) -> Result<(), ImageError> {
-        let current_track = &self.tracks[self.current_track];
-        let current_track_range_end = current_track.start_lba + current_track.track_info.frames;
-        if !(current_track.start_lba..current_track_range_end).contains(&lba) {
-            if let Some(index) = self.tracks.iter().position(
-                |x| lba >= x.start_lba && lba < (x.start_lba + x.track_info.frames)
-            ) {
-                self.current_track = index;
-                Ok(())
+        self.current_track = self.track_for_lba(lba).ok_or(ImageError::OutOfRange)?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "multithreading"))]
+    fn read_hunk(&mut self, hunk_no: u32) -> Result<(), ChdImageError> {
+        self.chd.hunk(hunk_no)?.read_hunk_in(&mut self.comp_buf, &mut self.hunk)?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "multithreading"))]
+    fn read_random_hunk(&mut self, hunk_no: u32) -> Result<(), ChdImageError> {
+        self.random_hunk_no = None;
+        self.chd.hunk(hunk_no)?.read_hunk_in(&mut self.comp_buf, &mut self.random_hunk)?;
+        self.random_hunk_no = Some(hunk_no);
+        Ok(())
+    }
+
+    #[cfg(feature = "multithreading")]
+    fn read_random_hunk(&mut self, hunk_no: u32) -> Result<(), ChdImageError> {
+        self.random_hunk_no = None;
+        if let Some(hunk) = self.hunk_reader.get_hunk_from_cache(hunk_no) {
+            self.random_hunk = hunk;
+        } else {
+            self.hunk_reader.send_read_hunk_command(hunk_no);
+            let completion = self.hunk_reader.recv_completion().map_err(ChdImageError::HunkRecvError)?;
+            let hunk_no = completion?;
+            self.random_hunk = self.hunk_reader.get_hunk_from_cache(hunk_no)
+                                   .expect("BUG: Hunk not in cache even though it should be");
+        }
+        self.random_hunk_no = Some(hunk_no);
+        Ok(())
+    }
+
+    // Waits for the hunk read issued for the current position to complete
+    #[cfg(feature = "multithreading")]
+    fn finish_pending_read(&mut self) -> Result<(), ImageError> {
+        if self.hunk_reader.hunk_read_pending() {
+            let now = std::time::Instant::now();
+            let recv = self.hunk_reader.recv_completion();
+            if let Ok(completion) = recv {
+                if let Ok(hunk_no) = completion {
+                    assert_eq!(self.current_hunk_no, Some(hunk_no));
+                    self.hunk = self.hunk_reader.get_hunk_from_cache(hunk_no)
+                                    .expect("BUG: Hunk not in cache even though it should be");
+                    debug!("Receiving hunk took {:?}", now.elapsed());
+                } else {
+                    self.current_hunk_no = None;
+                    return Err(ChdImageError::ChdError(completion.unwrap_err()).into());
+                }
             } else {
-                Err(ImageError::OutOfRange)
+                self.current_hunk_no = None;
+                return Err(ChdImageError::HunkRecvError(recv.unwrap_err()).into());
             }
-        } else {
-            Ok(())
         }
+        Ok(())
     }
 
-    #[cfg(not(feature = "multithreading"))]
-    fn read_hunk(&mut self, hunk_no: u32) -> Result<usize, chd_rs::Error> {
-        self.chd.hunk(hunk_no)?.read_hunk_in(&mut self.comp_buf, &mut self.hunk)
+    // Copies a sector out of a hunk, swapping audio samples to little endian
+    fn copy_sector_from_hunk(hunk: &[u8], sector_start: usize, track_type: TrackType, buf: &mut [u8]) {
+        buf.copy_from_slice(&hunk[sector_start..sector_start + 2352]);
+        if track_type == TrackType::Audio {
+            for x in buf.chunks_exact_mut(2) {
+                x.swap(0, 1);
+            }
+        }
     }
 
     #[cfg(feature = "multithreading")]
@@ -324,6 +442,25 @@ impl ChdImage {
         }
     }
 
+    // Makes sure the hunk containing the current sector has been read,
+    // returning the offset of the sector in it.
+    fn current_sector_start(&mut self) -> Result<usize, ImageError> {
+        let current_track = &self.tracks[self.current_track];
+        let current_file_lba = self.current_lba + current_track.padding_offset - FIRST_TRACK_PREGAP;
+        let sector_in_hunk = current_file_lba % self.sectors_per_hunk;
+
+        if self.current_hunk_no.is_none() {
+            warn!("Last read of this hunk failed, retrying");
+            self.set_location_lba(self.current_lba)?;
+        }
+        assert_eq!(self.current_hunk_no, Some(self.hunk_no_for_lba(self.current_lba)?));
+
+        #[cfg(feature = "multithreading")]
+        self.finish_pending_read()?;
+
+        Ok((sector_in_hunk * BYTES_PER_SECTOR) as usize)
+    }
+
     fn set_location_lba(&mut self, lba: u32) -> Result<(), ImageError> {
         self.current_lba = lba;
         // Set this to None so any upcoming errors making us return early don't
@@ -345,7 +482,7 @@ impl ChdImage {
         if hunk_no != current_hunk_no.unwrap_or(u32::MAX) {
             if let Err(e) = self.read_hunk(hunk_no) {
                 debug!("set_location_lba: {:?}", e);
-                return Err(ChdImageError::from(e).into());
+                return Err(e.into());
             }
         }
         self.current_hunk_no = Some(hunk_no);
@@ -358,12 +495,22 @@ impl Image for ChdImage {
         self.tracks.len()
     }
 
-    fn current_subchannel_q_valid(&self) -> bool {
-        if let Some(ref invalid_subq_lbas) = self.invalid_subq_lbas {
-            !invalid_subq_lbas.contains(&self.current_lba)
-        } else {
-            true
-        }
+    fn sbi_records(&self) -> Option<&SbiRecords> {
+        self.sidecars.sbi_records.as_ref()
+    }
+
+    fn sidecars(&self) -> &[Sidecar] {
+        &self.sidecars.loaded
+    }
+
+    fn failed_sidecars(&self) -> &[(Sidecar, String)] {
+        &self.sidecars.failed
+    }
+
+    // CHD files have no metadata for CD-TEXT, so it can only come from a
+    // side-car file
+    fn cd_text(&self) -> Option<&CdText> {
+        self.sidecars.cd_text.as_ref()
     }
 
     fn current_track(&self) -> Result<u8, ImageError> {
@@ -371,20 +518,11 @@ impl Image for ChdImage {
     }
 
     fn current_index(&self) -> Result<u8, ImageError> {
-        let current_track = &self.tracks[self.current_track];
-        let track_local_lba = self.current_lba - current_track.start_lba;
-        let index = if track_local_lba > current_track.track_info.pregap.unwrap_or(0) {
-            1
-        } else {
-            0
-        };
-        Ok(index)
+        Ok(self.tracks[self.current_track].index_for_lba(self.current_lba))
     }
 
     fn current_track_local_msf(&self) -> Result<MsfIndex, ImageError> {
-        let current_track = &self.tracks[self.current_track];
-        let index01_lba =
-            current_track.start_lba + current_track.track_info.pregap.unwrap_or(150);
+        let index01_lba = self.tracks[self.current_track].index_one_lba();
 
         if self.current_lba < index01_lba {
             // Negative MSFs are (100,0,0) - x
@@ -410,21 +548,57 @@ impl Image for ChdImage {
     }
 
     fn track_start(&self, track: u8) -> Result<MsfIndex, ImageError> {
-        // Track 0: Special case for PlayStation, return length of whole disc
-        // TODO: Make this less ugly?
-        if track == 0 {
-            let len = self.num_hunks * self.hunk_len;
-            let num_sectors = FIRST_TRACK_PREGAP + len / BYTES_PER_SECTOR;
-            Ok(MsfIndex::from_lba(num_sectors)?)
-        } else if track <= self.tracks.len() as u8 {
-            let track = &self.tracks[track as usize - 1];
-            let start_lba_index01 =
-                track.start_lba + track.track_info.pregap.unwrap_or(150);
-            debug!("track_start: {:?} {:?}", track, MsfIndex::from_lba(start_lba_index01));
-            Ok(MsfIndex::from_lba(start_lba_index01)?)
-        } else {
-            Err(ImageError::OutOfRange)
+        let track = self.tracks.get((track as usize).wrapping_sub(1)).ok_or(ImageError::OutOfRange)?;
+        let start_lba_index01 = track.index_one_lba();
+        debug!("track_start: {:?} {:?}", track, MsfIndex::from_lba(start_lba_index01));
+        Ok(MsfIndex::from_lba(start_lba_index01)?)
+    }
+
+    fn track_index_start(&self, track: u8, index: u8) -> Result<MsfIndex, ImageError> {
+        let track_no = (track as usize).wrapping_sub(1);
+        let track = self.tracks.get(track_no).ok_or(ImageError::OutOfRange)?;
+        let first_lba = self.track_first_lba(track_no);
+        match index {
+            0 if track.index_one_lba() > first_lba => Ok(MsfIndex::from_lba(first_lba)?),
+            1 => Ok(MsfIndex::from_lba(track.index_one_lba())?),
+            _ => Err(ImageError::OutOfRange),
+        }
+    }
+
+    fn lead_out(&self) -> Result<MsfIndex, ImageError> {
+        Ok(MsfIndex::from_lba(self.lead_out_lba())?)
+    }
+
+    fn toc(&self) -> Result<Toc, ImageError> {
+        let mut tracks = Vec::new();
+        for (i, track) in self.tracks.iter().enumerate() {
+            let first_lba = self.track_first_lba(i);
+            let index_one = track.index_one_lba();
+            let mut indices = Vec::new();
+            if index_one > first_lba {
+                indices.push((0, MsfIndex::from_lba(first_lba)?));
+            }
+            indices.push((1, MsfIndex::from_lba(index_one)?));
+            tracks.push(TrackInfo {
+                number: i as u8 + 1,
+                session: 1,
+                track_type: track.track_type,
+                flags: TrackFlags::empty(),
+                control: TrackFlags::empty().control(track.track_type),
+                start: MsfIndex::from_lba(index_one)?,
+                length: track.start_lba + track.track_info.frames - index_one,
+                indices,
+                pregap: index_one - first_lba,
+                postgap: track.track_info.postgap.unwrap_or(0),
+            });
         }
+        Ok(Toc::from_tracks(tracks, &[self.lead_out_lba()])?)
+    }
+
+    // CHD files don't store any track flags
+    fn track_flags(&self, track: u8) -> Result<TrackFlags, ImageError> {
+        self.tracks.get((track as usize).wrapping_sub(1)).ok_or(ImageError::OutOfRange)?;
+        Ok(TrackFlags::empty())
     }
 
     fn set_location(&mut self, target: MsfIndex) -> Result<(), ImageError> {
@@ -440,6 +614,7 @@ impl Image for ChdImage {
 
     fn advance_position(&mut self) -> Result<Option<Event>, ImageError> {
         let old_track = self.current_track;
+        let old_index = self.tracks[old_track].index_for_lba(self.current_lba);
         let res = self.set_location_lba(self.current_lba + 1);
         if let Err(e) = res {
             if let ImageError::OutOfRange = e {
@@ -449,6 +624,8 @@ impl Image for ChdImage {
             }
         } else if self.current_track != old_track {
             Ok(Some(Event::TrackChange))
+        } else if self.tracks[old_track].index_for_lba(self.current_lba) != old_index {
+            Ok(Some(Event::IndexChange))
         } else {
             Ok(None)
         }
@@ -470,46 +647,231 @@ impl Image for ChdImage {
             buf.fill(0);
             return Ok(());
         }
-        let current_track = &self.tracks[self.current_track];
-        let current_file_lba = self.current_lba + current_track.padding_offset - FIRST_TRACK_PREGAP;
-        let sector_in_hunk = current_file_lba % self.sectors_per_hunk;
-        let sector_start = (sector_in_hunk * BYTES_PER_SECTOR) as usize;
+        let sector_start = self.current_sector_start()?;
+        let track_type = self.tracks[self.current_track].track_type;
+        Self::copy_sector_from_hunk(&self.hunk, sector_start, track_type, buf);
+        Ok(())
+    }
 
-        if self.current_hunk_no.is_none() {
-            warn!("Last read of this hunk failed, retrying");
-            self.set_location_lba(self.current_lba)?;
+    fn copy_current_subchannel_unpatched(&mut self, buf: &mut [u8; 96]) -> Result<(), ImageError> {
+        let layout = match self.tracks[self.current_track].track_info.sub_type.as_str() {
+            // Cooked, i.e. 12 bytes for each channel
+            "RW" => Some(SubchannelLayout::Deinterleaved),
+            "RW_RAW" => Some(SubchannelLayout::Interleaved),
+            _ => None,
+        };
+        match layout {
+            Some(layout) if self.current_lba >= FIRST_TRACK_PREGAP => {
+                let sector_start = self.current_sector_start()?;
+                let stored: &[u8; 96] = self.hunk[sector_start + 2352..sector_start + BYTES_PER_SECTOR as usize]
+                    .try_into().unwrap();
+                match layout {
+                    SubchannelLayout::Interleaved => *buf = *stored,
+                    SubchannelLayout::Deinterleaved => subchannel::interleave(stored, buf),
+                }
+            }
+            _ => subchannel::synthesize(&*self, buf)?,
         }
-        assert_eq!(self.current_hunk_no, Some(self.hunk_no_for_lba(self.current_lba)?));
+        Ok(())
+    }
+
+    fn read_sector_at(&mut self, lba: u32, buf: &mut [u8]) -> Result<(), ImageError> {
+        if buf.len() != 2352 {
+            return Err(ChdImageError::WrongBufferSize.into())
+        }
+        if lba < FIRST_TRACK_PREGAP {
+            buf.fill(0);
+            return Ok(());
+        }
+        let track = &self.tracks[self.track_for_lba(lba).ok_or(ImageError::OutOfRange)?];
+        let track_type = track.track_type;
+        let file_lba = lba + track.padding_offset - FIRST_TRACK_PREGAP;
+        let hunk_no = file_lba / self.sectors_per_hunk;
+        let sector_start = ((file_lba % self.sectors_per_hunk) * BYTES_PER_SECTOR) as usize;
 
+        // Make sure `hunk` is valid before using it
         #[cfg(feature = "multithreading")]
-        if self.hunk_reader.hunk_read_pending() {
-            let now = std::time::Instant::now();
-            let recv = self.hunk_reader.recv_completion();
-            if let Ok(completion) = recv {
-                if let Ok(hunk_no) = completion {
-                    assert_eq!(self.current_hunk_no, Some(hunk_no));
-                    self.hunk = self.hunk_reader.get_hunk_from_cache(hunk_no)
-                                    .expect("BUG: Hunk not in cache even though it should be");
-                    debug!("Receiving hunk took {:?}", now.elapsed());
+        self.finish_pending_read()?;
+
+        if self.current_hunk_no == Some(hunk_no) {
+            Self::copy_sector_from_hunk(&self.hunk, sector_start, track_type, buf);
+        } else {
+            if self.random_hunk_no != Some(hunk_no) {
+                self.read_random_hunk(hunk_no)?;
+            }
+            Self::copy_sector_from_hunk(&self.random_hunk, sector_start, track_type, buf);
+        }
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::chd::*;
+    use crate::cue::Cuesheet;
+    use crate::track_sha1s;
+
+    const SECTORS_PER_HUNK: usize = 8;
+
+    // Deinterleaved subchannel data stored for every sector of tracks with
+    // subcode
+    const SUBCODE: [u8; 96] = {
+        let mut subcode = [0u8; 96];
+        let mut i = 0;
+        while i < 96 {
+            subcode[i] = i as u8;
+            i += 1;
+        }
+        subcode
+    };
+
+    // Builds an uncompressed v5 CHD from (type, subtype, pregap, sector data)
+    // tuples, laid out the way chdman does: audio byte swapped, each track
+    // padded to a multiple of 4 sectors and the pregap stored as part of the
+    // track. Tracks without a pregap are described in the old metadata
+    // format. Tracks with subcode get `SUBCODE` in the layout of their
+    // subtype.
+    fn build_chd(tracks: &[(&str, &str, Option<u32>, &[u8])]) -> Vec<u8> {
+        let hunk_len = SECTORS_PER_HUNK * BYTES_PER_SECTOR as usize;
+        let mut metadata = Vec::new();
+        let mut data = Vec::new();
+        for (i, &(track_type, sub_type, pregap, sectors)) in tracks.iter().enumerate() {
+            let frames = sectors.len() / 2352;
+            let (tag, value) = match pregap {
+                Some(pregap) => (b"CHT2", format!(
+                    "TRACK:{} TYPE:{} SUBTYPE:{} FRAMES:{} PREGAP:{} PGTYPE:V{} PGSUB:{} POSTGAP:0\0",
+                    i + 1, track_type, sub_type, frames, pregap, track_type, sub_type)),
+                None => (b"CHTR", format!(
+                    "TRACK:{} TYPE:{} SUBTYPE:{} FRAMES:{}\0", i + 1, track_type, sub_type, frames)),
+            };
+            let next = if i + 1 < tracks.len() { 124 + metadata.len() + 16 + value.len() } else { 0 };
+            metadata.extend(tag);
+            metadata.extend((0x0100_0000 | value.len() as u32).to_be_bytes());
+            metadata.extend((next as u64).to_be_bytes());
+            metadata.extend(value.as_bytes());
+
+            for sector in sectors.chunks(2352) {
+                if track_type == "AUDIO" {
+                    data.extend(sector.chunks(2).flat_map(|x| [x[1], x[0]]));
                 } else {
-                    self.current_hunk_no = None;
-                    return Err(ChdImageError::ChdError(completion.unwrap_err()).into());
+                    data.extend(sector);
                 }
-            } else {
-                self.current_hunk_no = None;
-                return Err(ChdImageError::HunkRecvError(recv.unwrap_err()).into());
+                data.extend(match sub_type {
+                    "RW" => SUBCODE,
+                    "RW_RAW" => {
+                        let mut raw = [0u8; 96];
+                        subchannel::interleave(&SUBCODE, &mut raw);
+                        raw
+                    }
+                    _ => [0u8; 96],
+                });
             }
+            data.resize(data.len() + (4 - frames % 4) % 4 * BYTES_PER_SECTOR as usize, 0);
         }
+        let logical_len = data.len();
+        let num_hunks = logical_len.div_ceil(hunk_len);
+        data.resize(num_hunks * hunk_len, 0);
+
+        let map_offset = 124 + metadata.len();
+        let mut chd = b"MComprHD".to_vec();
+        chd.extend(124u32.to_be_bytes());
+        chd.extend(5u32.to_be_bytes());
+        chd.extend([0u8; 16]);
+        chd.extend((logical_len as u64).to_be_bytes());
+        chd.extend((map_offset as u64).to_be_bytes());
+        chd.extend(124u64.to_be_bytes());
+        chd.extend((hunk_len as u32).to_be_bytes());
+        chd.extend(BYTES_PER_SECTOR.to_be_bytes());
+        chd.extend([0u8; 60]);
+        chd.extend(metadata);
+        // Hunk data starts at the second hunk sized block of the file
+        for hunk in 0..num_hunks {
+            chd.extend((hunk as u32 + 1).to_be_bytes());
+        }
+        chd.resize(hunk_len, 0);
+        chd.extend(data);
+        chd
+    }
 
-        let sector = &self.hunk[sector_start..sector_start + 2352];
-        buf.clone_from_slice(sector);
+    #[test]
+    fn lead_out_matches_cuesheet() {
+        let bin: Vec<u8> = (1..=7u8).flat_map(|x| [x; 2352]).collect();
+        let mut chd = ChdImage::from_reader(Cursor::new(build_chd(&[
+            ("MODE1_RAW", "NONE", Some(0), &bin[..3 * 2352]),
+            ("AUDIO", "NONE", Some(2), &bin[3 * 2352..]),
+        ]))).unwrap();
+        let mut cue = Cuesheet::from_str_with_resolver("\
+FILE \"test.bin\" BINARY
+  TRACK 01 MODE1/2352
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    INDEX 00 00:00:03
+    INDEX 01 00:00:05
+", |_| Ok(Box::new(Cursor::new(bin.clone())) as Box<dyn ReadSeek>)).unwrap();
+
+        // The padding sector after track 1 isn't part of the disc
+        assert_eq!(chd.lead_out().unwrap(), MsfIndex::from_lba(157).unwrap());
+        assert_eq!(chd.lead_out().unwrap(), cue.lead_out().unwrap());
+        assert_eq!(chd.toc().unwrap(), cue.toc().unwrap());
+        assert!(chd.track_start(0).is_err());
+        assert!(cue.track_start(0).is_err());
+        assert_eq!(track_sha1s(&mut chd).unwrap(), track_sha1s(&mut cue).unwrap());
+    }
 
-        if self.current_track_type().unwrap() == TrackType::Audio {
-            for x in buf.chunks_exact_mut(2) {
-                x.swap(0, 1);
-            }
+    #[test]
+    fn subchannel_data() {
+        for sub_type in ["RW", "RW_RAW"] {
+            let mut chd = ChdImage::from_reader(Cursor::new(build_chd(&[
+                ("MODE1_RAW", "NONE", Some(0), &[1u8; 2 * 2352]),
+                ("AUDIO", sub_type, Some(0), &[2u8; 2 * 2352]),
+            ]))).unwrap();
+
+            // Nothing stored for this track, so P and Q are synthesized
+            let mut subcode = [0u8; 96];
+            chd.copy_current_subchannel_deinterleaved(&mut subcode).unwrap();
+            assert_eq!(&subcode[12..22], &[0x41, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00]);
+            assert!(subcode[24..].iter().all(|&x| x == 0));
+
+            chd.set_location_to_track(2).unwrap();
+            chd.copy_current_subchannel_deinterleaved(&mut subcode).unwrap();
+            assert_eq!(subcode, SUBCODE, "{}", sub_type);
+            let mut interleaved = [0u8; 96];
+            subchannel::interleave(&SUBCODE, &mut interleaved);
+            chd.copy_current_subchannel(&mut subcode).unwrap();
+            assert_eq!(subcode, interleaved, "{}", sub_type);
         }
+    }
 
-        Ok(())
+    #[test]
+    fn open_with_parent_and_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.chd");
+        std::fs::write(&path, build_chd(&[("MODE1_RAW", "NONE", Some(0), &[1u8; 2 * 2352])])).unwrap();
+        std::fs::write(path.with_extension("sbi"), b"broken").unwrap();
+        let no_parents: &[&Path] = &[];
+
+        let chd = ChdImage::open_with_parent(&path, no_parents).unwrap();
+        assert_eq!(chd.failed_sidecars().len(), 1);
+        let result = ChdImage::open_with_parent_and_options(&path, no_parents, OpenOptions::new().strict(true));
+        assert!(matches!(result, Err(ChdImageError::SidecarError(_))));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn old_metadata_without_pregap() {
+        let mut chd = ChdImage::from_reader(Cursor::new(build_chd(&[
+            ("MODE1_RAW", "NONE", None, &[1u8; 3 * 2352]),
+            ("AUDIO", "NONE", None, &[2u8; 2 * 2352]),
+        ]))).unwrap();
+
+        assert_eq!(chd.track_start(1).unwrap(), MsfIndex::from_lba(150).unwrap());
+        assert_eq!(chd.track_start(2).unwrap(), MsfIndex::from_lba(153).unwrap());
+        assert!(chd.track_index_start(2, 0).is_err());
+        let toc = chd.toc().unwrap();
+        assert_eq!((toc.track(2).unwrap().pregap, toc.track(2).unwrap().length), (0, 2));
+
+        chd.set_location(MsfIndex::from_lba(152).unwrap()).unwrap();
+        assert_eq!(chd.advance_position().unwrap(), Some(Event::TrackChange));
+        assert_eq!(chd.current_index().unwrap(), 1);
+    }
+}
@@ -5,6 +5,8 @@ use log::{debug, error};
 use chd_rs::Chd;
 use lru::LruCache;
 
+use crate::ReadSeek;
+
 
 const NUM_HUNKS_READAHEAD: u32 = 8;
 const NUM_READAHEAD_HUNKS_LOW_WATER: u32 = 2;
@@ -12,8 +14,10 @@ const NUM_CMD_SLOTS: usize = 2;
 
 const CACHE_CAPACITY: usize = 100;
 
+type HunkCache = Arc<Mutex<LruCache<u32, Vec<u8>>>>;
+
 struct ChdThread {
-    chd: Chd<std::fs::File>,
+    chd: Chd<Box<dyn ReadSeek>>,
     cmd_receiver: mpsc::Receiver<Command>,
     cmd_while_prefetching: Option<Command>,
     hunk_sender: mpsc::SyncSender<Result<u32, chd_rs::Error>>,
@@ -29,10 +33,10 @@ struct ChdThread {
 }
 
 impl ChdThread {
-    fn start(chd: Chd<std::fs::File>,
+    fn start(chd: Chd<Box<dyn ReadSeek>>,
         cmd_receiver: mpsc::Receiver<Command>,
         hunk_sender: mpsc::SyncSender<Result<u32, chd_rs::Error>>)
-        -> (thread::JoinHandle<()>, Arc<Mutex<LruCache<u32, Vec<u8>>>>)
+        -> (thread::JoinHandle<()>, HunkCache)
     {
         let num_hunks = chd.header().hunk_count();
         let hunk_cache = Arc::new(Mutex::new(LruCache::new(std::num::NonZero::new(CACHE_CAPACITY).unwrap())));
@@ -140,14 +144,10 @@ impl ChdThread {
         if !hunk_contained {
             let mut buf = self.chd.get_hunksized_buffer();
             let t = std::time::Instant::now();
-            let result = self.chd.hunk(hunk_no)?.read_hunk_in(&mut self.comp_buf, &mut buf);
-            if result.is_ok() {
-                self.hunk_cache.lock().unwrap().put(hunk_no, buf);
-                debug!("Reading hunk {} took {:?}", hunk_no, t.elapsed());
-                Ok(hunk_no)
-            } else {
-                Err(result.unwrap_err())
-            }
+            self.chd.hunk(hunk_no)?.read_hunk_in(&mut self.comp_buf, &mut buf)?;
+            self.hunk_cache.lock().unwrap().put(hunk_no, buf);
+            debug!("Reading hunk {} took {:?}", hunk_no, t.elapsed());
+            Ok(hunk_no)
         } else {
             // Hunk already in cache
             Ok(hunk_no)
@@ -165,7 +165,7 @@ pub struct ChdHunkReader {
 }
 
 impl ChdHunkReader {
-    pub fn new(chd: Chd<std::fs::File>) -> ChdHunkReader {
+    pub fn new(chd: Chd<Box<dyn ReadSeek>>) -> ChdHunkReader {
         let (cmd_sender, cmd_receiver) = mpsc::sync_channel(NUM_CMD_SLOTS);
         let (completion_sender, completion_receiver) = mpsc::sync_channel(1);
 
@@ -209,7 +209,7 @@ impl ChdHunkReader {
 
     pub fn get_hunk_from_cache(&mut self, hunk_no: u32) -> Option<Vec<u8>> {
         let mut cache = self.cache.lock().unwrap();
-        cache.get(&hunk_no).map(|x| x.clone())
+        cache.get(&hunk_no).cloned()
     }
 }
 
@@ -0,0 +1,459 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+
+use log::{debug, info, warn};
+
+use thiserror::Error;
+
+use vec_map::VecMap;
+
+use crate::disc::{Disc, DiscTrack, SubchannelData, SubchannelLayout, TrackData};
+use crate::sector::SectorFormat;
+use crate::{OpenOptions, TrackFlags, TrackType};
+
+
+#[derive(Debug, Error)]
+pub enum CloneCdError {
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    SidecarError(#[from] crate::SidecarError),
+    #[error("Invalid line {0} in CCD file")]
+    InvalidLine(usize),
+    #[error("Missing key {1} in section [{0}] of CCD file")]
+    MissingKey(String, String),
+    #[error("Invalid value for key {1} in section [{0}] of CCD file")]
+    InvalidValue(String, String),
+    #[error("Unsupported track mode {0} in CCD file")]
+    UnsupportedTrackMode(i64),
+    #[error("Invalid track number in CCD file")]
+    InvalidTrackNumber,
+    #[error("No tracks in CCD file")]
+    NoTracks,
+    #[error("No lead-out entry for session {0} in CCD file")]
+    MissingLeadOut(u8),
+}
+
+// Sections of the INI-style CCD file, keys are converted to upper case
+type Section = BTreeMap<String, String>;
+
+struct CcdFile {
+    sections: BTreeMap<String, Section>,
+}
+
+impl CcdFile {
+    fn parse(s: &str) -> Result<CcdFile, CloneCdError> {
+        let mut sections = BTreeMap::new();
+        let mut current_section: Option<(String, Section)> = None;
+
+        for (line_no, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                if let Some((name, section)) = current_section.take() {
+                    sections.insert(name, section);
+                }
+                let name = line[1..line.len() - 1].trim().to_uppercase();
+                current_section = Some((name, Section::new()));
+            } else if let (Some((key, value)), Some((_, section))) = (line.split_once('='), current_section.as_mut()) {
+                section.insert(key.trim().to_uppercase(), value.trim().to_string());
+            } else {
+                return Err(CloneCdError::InvalidLine(line_no + 1));
+            }
+        }
+        if let Some((name, section)) = current_section {
+            sections.insert(name, section);
+        }
+
+        Ok(CcdFile { sections })
+    }
+
+    fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.get(name)
+    }
+
+    fn entries(&self) -> impl Iterator<Item = (&String, &Section)> {
+        self.sections.iter().filter(|(name, _)| name.starts_with("ENTRY "))
+    }
+}
+
+// Values are given either in decimal or in hexadecimal with a 0x prefix
+fn parse_value(s: &str) -> Option<i64> {
+    let s = s.trim();
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn get_value(section: &Section, section_name: &str, key: &str) -> Result<Option<i64>, CloneCdError> {
+    match section.get(key) {
+        Some(value) => parse_value(value)
+            .map(Some)
+            .ok_or_else(|| CloneCdError::InvalidValue(section_name.to_string(), key.to_string())),
+        None => Ok(None),
+    }
+}
+
+fn require_value(section: &Section, section_name: &str, key: &str) -> Result<i64, CloneCdError> {
+    get_value(section, section_name, key)?
+        .ok_or_else(|| CloneCdError::MissingKey(section_name.to_string(), key.to_string()))
+}
+
+// Converts a (relative) LBA as found in CCD files into an absolute one
+fn absolute_lba(section_name: &str, key: &str, lba: i64) -> Result<u32, CloneCdError> {
+    let lba = lba + 150;
+    if lba < 0 || lba > u32::MAX as i64 {
+        return Err(CloneCdError::InvalidValue(section_name.to_string(), key.to_string()));
+    }
+    Ok(lba as u32)
+}
+
+/// An image in CloneCD format, consisting of the `.ccd` table of contents,
+/// the `.img` file containing raw 2352 byte sectors and an optional `.sub`
+/// file with the subchannel data of every sector.
+pub struct CloneCdImage {
+    disc: Disc,
+}
+
+impl CloneCdImage {
+    /// Opens the image described by the `.ccd` file at `path`. The `.img` and
+    /// `.sub` files are expected next to it with the same base name.
+    pub fn open<P>(path: P) -> Result<CloneCdImage, CloneCdError>
+        where P: AsRef<Path>
+    {
+        Self::_open(path.as_ref(), &OpenOptions::new())
+    }
+
+    /// Like `open`, but loads the side-car files according to `options`.
+    pub fn open_with_options<P>(path: P, options: &OpenOptions) -> Result<CloneCdImage, CloneCdError>
+        where P: AsRef<Path>
+    {
+        Self::_open(path.as_ref(), options)
+    }
+
+    fn _open(path: &Path, options: &OpenOptions) -> Result<CloneCdImage, CloneCdError> {
+        let ccd = CcdFile::parse(&std::fs::read_to_string(path)?)?;
+
+        let img_file = File::open(path.with_extension("img"))?;
+        let img_sectors = (img_file.metadata()?.len() / 2352) as u32;
+        let sub_file = match File::open(path.with_extension("sub")) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                info!("No subchannel data loaded: {}", e);
+                None
+            }
+        };
+
+        // Point -> (session, flags, absolute LBA of index 01)
+        let mut toc_tracks: BTreeMap<u8, (u8, TrackFlags, u32)> = BTreeMap::new();
+        // Session -> absolute LBA of its lead-out
+        let mut lead_outs: BTreeMap<u8, u32> = BTreeMap::new();
+        for (name, entry) in ccd.entries() {
+            let point = require_value(entry, name, "POINT")?;
+            let session = get_value(entry, name, "SESSION")?.unwrap_or(1);
+            if !(1..=99).contains(&session) {
+                return Err(CloneCdError::InvalidValue(name.clone(), "SESSION".to_string()));
+            }
+            if (1..=99).contains(&point) {
+                let plba = require_value(entry, name, "PLBA")?;
+                let control = get_value(entry, name, "CONTROL")?.unwrap_or(0);
+                let flags = TrackFlags::from_control(control as u8);
+                toc_tracks.insert(point as u8, (session as u8, flags, absolute_lba(name, "PLBA", plba)?));
+            } else if point == 0xa2 {
+                let plba = absolute_lba(name, "PLBA", require_value(entry, name, "PLBA")?)?;
+                lead_outs.insert(session as u8, plba);
+            }
+        }
+        if toc_tracks.is_empty() {
+            return Err(CloneCdError::NoTracks);
+        }
+        if toc_tracks.keys().zip(1..).any(|(&point, n)| point != n) {
+            return Err(CloneCdError::InvalidTrackNumber);
+        }
+
+        // The image file only contains the sessions' data one after another,
+        // the lead-out and lead-in areas in between aren't stored.
+
+        // Number of sectors stored for the sessions before the current one
+        let mut session_offset = 0;
+        // Absolute LBA of the first stored sector of the current session
+        let mut session_start = 150;
+        let mut tracks: Vec<DiscTrack> = Vec::new();
+        for (&track_no, &(session, flags, index_one)) in toc_tracks.iter() {
+            let section_name = format!("TRACK {}", track_no);
+            let track_section = ccd.section(&section_name);
+
+            let mut indices = VecMap::new();
+            indices.insert(1, index_one);
+            let track_type = match track_section.map(|x| get_value(x, &section_name, "MODE")).transpose()?.flatten() {
+                Some(0) => TrackType::Audio,
+                Some(1) => TrackType::Mode1,
+                Some(2) => TrackType::Mode2,
+                Some(mode) => return Err(CloneCdError::UnsupportedTrackMode(mode)),
+                None => {
+                    warn!("No mode given for track {}, assuming Mode1", track_no);
+                    TrackType::Mode1
+                }
+            };
+            if let Some(section) = track_section {
+                for (key, value) in section.iter() {
+                    if let Some(index) = key.strip_prefix("INDEX ") {
+                        let index: usize = index.trim().parse()
+                            .map_err(|_| CloneCdError::InvalidValue(section_name.clone(), key.clone()))?;
+                        let lba = parse_value(value)
+                            .ok_or_else(|| CloneCdError::InvalidValue(section_name.clone(), key.clone()))?;
+                        if index > 99 {
+                            return Err(CloneCdError::InvalidValue(section_name.clone(), key.clone()));
+                        }
+                        indices.insert(index, absolute_lba(&section_name, key, lba)?);
+                    }
+                }
+            }
+
+            // The first track additionally covers the two seconds before it,
+            // which aren't part of the image.
+            let start = if track_no == 1 {
+                0
+            } else {
+                *indices.get(0).unwrap_or(&index_one)
+            };
+            if start < index_one && !indices.contains_key(0) {
+                indices.insert(0, start);
+            }
+
+            let data_start = start.max(150);
+            if let Some(previous) = tracks.last_mut() {
+                if start <= previous.start {
+                    return Err(CloneCdError::InvalidValue(section_name, "INDEX 0".to_string()));
+                }
+                let end = if previous.session == session {
+                    start
+                } else {
+                    let lead_out = *lead_outs.get(&previous.session)
+                        .ok_or(CloneCdError::MissingLeadOut(previous.session))?;
+                    if lead_out <= previous.start || lead_out > start {
+                        return Err(CloneCdError::InvalidValue("ENTRY".to_string(), "PLBA".to_string()));
+                    }
+                    session_offset += lead_out - session_start;
+                    session_start = data_start;
+                    lead_out
+                };
+                previous.num_sectors = end - previous.start;
+                previous.data.num_sectors = end.saturating_sub(previous.data.start);
+            }
+
+            let stored_sector = (session_offset + data_start - session_start) as u64;
+            tracks.push(DiscTrack {
+                track_type,
+                session,
+                flags,
+                start,
+                num_sectors: 0,
+                indices,
+                data: TrackData {
+                    file: 0,
+                    start: data_start,
+                    num_sectors: 0,
+                    offset: stored_sector * 2352,
+                    sector_size: 2352,
+                    format: SectorFormat::Raw,
+                    subchannel: sub_file.as_ref().map(|_| SubchannelData {
+                        file: 1,
+                        offset: stored_sector * 96,
+                        stride: 96,
+                        layout: SubchannelLayout::Deinterleaved,
+                    }),
+                },
+            });
+        }
+        let last_track = tracks.last_mut().unwrap();
+        let lead_out = match lead_outs.get(&last_track.session) {
+            Some(&lead_out) => lead_out,
+            None => {
+                warn!("No lead-out entry in CCD file, using size of image file");
+                session_start + img_sectors.saturating_sub(session_offset)
+            }
+        };
+        if lead_out <= last_track.start {
+            return Err(CloneCdError::InvalidValue("ENTRY".to_string(), "PLBA".to_string()));
+        }
+        last_track.num_sectors = lead_out - last_track.start;
+        last_track.data.num_sectors = lead_out.saturating_sub(last_track.data.start);
+        debug!("CloneCD tracks: {:?}", tracks);
+
+        let sidecars = options.load_sidecars(path)?;
+
+        let mut files = vec![img_file];
+        files.extend(sub_file);
+
+        Ok(CloneCdImage {
+            disc: Disc::new(files, tracks, sidecars),
+        })
+    }
+
+    /// Returns whether a `.sub` file with subchannel data was found.
+    pub fn has_subchannel_data(&self) -> bool {
+        self.disc.has_subchannel_data()
+    }
+}
+
+crate::disc::forward_image_to_disc!(CloneCdImage);
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use crate::clonecd::*;
+    use crate::{Event, Image, MsfIndex, TrackFlags};
+
+    const CCD: &str = "\
+[CloneCD]
+Version=3
+[Disc]
+TocEntries=5
+Sessions=1
+[Session 1]
+PreGapMode=2
+[Entry 0]
+Session=1
+Point=0xa0
+Control=0x04
+PLBA=6750
+[Entry 1]
+Session=1
+Point=0xa2
+Control=0x04
+PLBA=20
+[Entry 2]
+Session=1
+Point=0x01
+Control=0x04
+PLBA=0
+[Entry 3]
+Session=1
+Point=0x02
+Control=0x02
+PLBA=12
+[TRACK 1]
+MODE=2
+INDEX 1=0
+[TRACK 2]
+MODE=0
+INDEX 0=10
+INDEX 1=12
+";
+
+    // Two sessions with one track each, the image contains the 10 sectors of
+    // the first and the 5 sectors of the second session.
+    const CCD_MULTISESSION: &str = "\
+[CloneCD]
+Version=3
+[Disc]
+TocEntries=4
+Sessions=2
+[Entry 0]
+Session=1
+Point=0xa2
+Control=0x04
+PLBA=10
+[Entry 1]
+Session=1
+Point=0x01
+Control=0x04
+PLBA=0
+[Entry 2]
+Session=2
+Point=0xa2
+Control=0x04
+PLBA=11405
+[Entry 3]
+Session=2
+Point=0x02
+Control=0x04
+PLBA=11402
+[TRACK 1]
+MODE=1
+INDEX 1=0
+[TRACK 2]
+MODE=1
+INDEX 0=11400
+INDEX 1=11402
+";
+
+    fn write_image(dir: &Path, ccd: &str, num_sectors: u8) -> std::path::PathBuf {
+        let ccd_path = dir.join("test.ccd");
+        std::fs::write(&ccd_path, ccd).unwrap();
+        let mut img = File::create(dir.join("test.img")).unwrap();
+        for i in 0..num_sectors {
+            img.write_all(&[i; 2352]).unwrap();
+        }
+        ccd_path
+    }
+
+    #[test]
+    fn clonecd_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut image = CloneCdImage::open(write_image(dir.path(), CCD, 20)).unwrap();
+
+        assert_eq!(image.num_tracks(), 2);
+        assert_eq!(image.first_track_type(), TrackType::Mode2);
+        assert_eq!(image.track_start(1).unwrap(), MsfIndex::new(0, 2, 0).unwrap());
+        assert_eq!(image.track_start(2).unwrap(), MsfIndex::from_lba(162).unwrap());
+        assert_eq!(image.lead_out().unwrap(), MsfIndex::from_lba(170).unwrap());
+        assert!(!image.has_subchannel_data());
+        assert!(image.track_flags(1).unwrap().is_empty());
+        assert_eq!(image.track_flags(2).unwrap(), TrackFlags::COPY_PERMITTED);
+        assert_eq!(image.toc().unwrap().track(2).unwrap().control, 0x02);
+
+        let mut buf = [0u8; 2352];
+        image.set_location(MsfIndex::from_lba(159).unwrap()).unwrap();
+        image.copy_current_sector(&mut buf).unwrap();
+        assert_eq!(buf[0], 9);
+        assert_eq!(image.advance_position().unwrap(), Some(Event::TrackChange));
+        assert_eq!(image.current_track().unwrap(), 2);
+        assert_eq!(image.current_index().unwrap(), 0);
+        assert_eq!(image.current_track_local_msf().unwrap(), MsfIndex::new(99, 59, 73).unwrap());
+        assert_eq!(image.current_track_type().unwrap(), TrackType::Audio);
+        image.copy_current_sector(&mut buf).unwrap();
+        assert_eq!(buf[0], 10);
+
+        image.set_location_to_track(2).unwrap();
+        assert_eq!(image.current_index().unwrap(), 1);
+        for _ in 0..7 {
+            assert_eq!(image.advance_position().unwrap(), None);
+        }
+        assert_eq!(image.advance_position().unwrap(), Some(Event::EndOfDisc));
+    }
+
+    #[test]
+    fn clonecd_multisession() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut image = CloneCdImage::open(write_image(dir.path(), CCD_MULTISESSION, 15)).unwrap();
+
+        assert_eq!(image.lead_out().unwrap(), MsfIndex::from_lba(11555).unwrap());
+        let toc = image.toc().unwrap();
+        assert_eq!(toc.sessions.len(), 2);
+        assert_eq!(toc.sessions[0].lead_out, MsfIndex::from_lba(160).unwrap());
+        assert_eq!(toc.sessions[1].lead_out, MsfIndex::from_lba(11555).unwrap());
+        assert_eq!(toc.track(1).unwrap().length, 10);
+        assert_eq!(toc.track(2).unwrap().length, 3);
+
+        let mut buf = [0u8; 2352];
+        image.set_location(MsfIndex::from_lba(159).unwrap()).unwrap();
+        image.copy_current_sector(&mut buf).unwrap();
+        assert_eq!(buf[0], 9);
+        assert_eq!(image.advance_position().unwrap(), Some(Event::TrackChange));
+        assert_eq!(image.current_global_msf().unwrap(), MsfIndex::from_lba(11550).unwrap());
+        image.copy_current_sector(&mut buf).unwrap();
+        assert_eq!(buf[0], 10);
+
+        image.set_location_to_track(2).unwrap();
+        image.copy_current_sector(&mut buf).unwrap();
+        assert_eq!(buf[0], 12);
+    }
+}
@@ -1,27 +1,42 @@
-use std::collections::BTreeSet;
+mod audio_file;
+#[cfg(feature = "flac")]
+mod flac_file;
+
+use std::fmt;
 use std::fs::File;
-use std::io;
 use std::io::{Read, Seek, SeekFrom};
-use std::path::Path;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::str;
 
-use crate::{debug, error, info, warn};
+use crate::{debug, info, warn};
 
 use thiserror::Error;
 
 use vec_map::VecMap;
 
+use crate::cdtext::{CdText, CdTextError};
 use crate::index::{MsfIndex, MsfIndexError};
-use crate::{Event, Image, ImageError, TrackType};
+use crate::options::Sidecars;
+use crate::sbi::SbiRecords;
+use crate::sector::SectorFormat;
+use crate::subchannel;
+use crate::toc::{Toc, TrackFlags, TrackInfo};
+use crate::{Event, Image, ImageError, Metadata, OpenOptions, ReadSeek, Sidecar, SidecarKind, TrackMetadata, TrackType};
 
 
-// TODO: Rework these, most of these aren't really useful for users of the
-// crate I think...
+/// Errors from opening a cuesheet. Errors caused by a line of the cuesheet
+/// are returned as `ParseError`, which holds the position of the line and one
+/// of the other variants.
 #[derive(Debug, Error)]
 pub enum CueError {
+    #[error(transparent)]
+    ParseError(Box<CueParseError>),
     #[error("Error parsing MSF index")]
     MsfParseError(#[from] MsfIndexError),
     #[error(transparent)]
+    SidecarError(#[from] crate::SidecarError),
+    #[error(transparent)]
     ParseIntError(#[from] std::num::ParseIntError),
     #[error(transparent)]
     IoError(#[from] std::io::Error),
@@ -39,8 +54,19 @@ pub enum CueError {
     UnknownTrackType(String),
     #[error("Unknown bin mode {0} in cuesheet")]
     UnknownBinMode(String),
+    #[error("Unsupported bin mode {0:?} in cuesheet")]
+    UnsupportedBinMode(BinMode),
+    #[error("Invalid or truncated audio file")]
+    InvalidAudioFile,
+    #[error("Unsupported audio format, needs to be 44.1kHz 16 bit stereo PCM")]
+    UnsupportedAudioFormat,
+    #[cfg(feature = "flac")]
+    #[error("Error decoding FLAC file: {0}")]
+    FlacError(#[from] claxon::Error),
     #[error("Invalid PREGAP line in cuesheet")]
     InvalidPregapLine,
+    #[error("Invalid POSTGAP line in cuesheet")]
+    InvalidPostgapLine,
     #[error("Invalid INDEX line in cuesheet")]
     InvalidIndexLine,
     #[error("Invalid index number in cuesheet")]
@@ -49,14 +75,242 @@ pub enum CueError {
     NoBinFiles,
     #[error("Error parsing file name in cuesheet")]
     FileNameParseError,
+    #[error("Error parsing CD-TEXT file: {0}")]
+    CdTextError(#[from] CdTextError),
     #[error("Unexpected TRACK command in cuesheet")]
     TrackCommandWithoutBinFile,
     #[error("Unexpected INDEX command in cuesheet")]
     IndexCommandWithoutTrack,
+    #[error("Unexpected PREGAP or POSTGAP command in cuesheet")]
+    GapCommandWithoutTrack,
+    #[error("Unexpected FLAGS command in cuesheet")]
+    FlagsCommandWithoutTrack,
+    #[error("Unknown flag {0} in cuesheet")]
+    UnknownFlag(String),
+    #[error("Missing or unterminated string in {0} command in cuesheet")]
+    InvalidMetadataLine(String),
     #[error("Error parsing input as UTF-8")]
     Utf8Error(#[from] str::Utf8Error),
 }
 
+impl CueError {
+    /// Returns the error without the position of the line causing it.
+    pub fn kind(&self) -> &CueError {
+        match self {
+            CueError::ParseError(e) => &e.error,
+            e => e,
+        }
+    }
+
+    // Adds the line at `line_no` (starting at 1) in `cue` to the error unless
+    // it already has a position
+    fn at_line(self, cue: &str, line_no: usize) -> CueError {
+        match self {
+            CueError::ParseError(_) => self,
+            error => {
+                let line = cue.lines().nth(line_no - 1).unwrap_or_default();
+                CueError::ParseError(Box::new(CueParseError::new(line_no, line, error)))
+            }
+        }
+    }
+
+    fn with_path(self, path: &Path) -> CueError {
+        match self {
+            CueError::ParseError(mut e) => {
+                e.path = Some(path.to_path_buf());
+                CueError::ParseError(e)
+            }
+            e => e,
+        }
+    }
+}
+
+/// Error caused by a line of a cuesheet. It's displayed like a compiler
+/// diagnostic, quoting the line and marking the offending part.
+#[derive(Debug)]
+pub struct CueParseError {
+    /// Path of the cuesheet if it was opened from a file
+    pub path: Option<PathBuf>,
+    /// Number of the line, starting at 1
+    pub line: usize,
+    /// Byte range of the offending part within the line
+    pub span: Range<usize>,
+    pub line_text: String,
+    pub error: CueError,
+}
+
+impl CueParseError {
+    fn new(line_no: usize, line: &str, error: CueError) -> CueParseError {
+        CueParseError {
+            path: None,
+            line: line_no,
+            span: error_span(line, &error),
+            line_text: line.to_string(),
+            error,
+        }
+    }
+
+    /// Column of the start of the offending part, starting at 1
+    pub fn column(&self) -> usize {
+        column(&self.line_text, &self.span)
+    }
+}
+
+impl fmt::Display for CueParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Diagnostic {
+            level: "error",
+            message: &self.error,
+            path: self.path.as_deref(),
+            line: self.line,
+            span: &self.span,
+            line_text: &self.line_text,
+        }.fmt(f)
+    }
+}
+
+impl std::error::Error for CueParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Deviation from the cuesheet format accepted when parsing leniently
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum CueWarningKind {
+    #[error("Byte order mark at the start of the cuesheet")]
+    ByteOrderMark,
+    #[error("Ignoring unknown command {0} in cuesheet")]
+    UnknownCommand(String),
+    #[error("File name not enclosed in quotes in cuesheet")]
+    UnquotedFileName,
+    #[error("Quotes within file name in cuesheet")]
+    QuotesInFileName,
+    #[error("Whitespace around file name in cuesheet")]
+    WhitespaceAroundFileName,
+    /// The track number doesn't follow the previous one in the cuesheet,
+    /// tracks are numbered consecutively like the image's tracks are
+    #[error("Expected track number {expected} instead of {found} in cuesheet")]
+    TrackNumberGap { expected: u8, found: u8 },
+}
+
+/// Warning about a line of a cuesheet that was accepted despite not following
+/// the format. It's displayed like `CueParseError`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CueWarning {
+    /// Path of the cuesheet if it was opened from a file
+    pub path: Option<PathBuf>,
+    /// Number of the line, starting at 1
+    pub line: usize,
+    /// Byte range of the offending part within the line
+    pub span: Range<usize>,
+    pub line_text: String,
+    pub kind: CueWarningKind,
+}
+
+impl CueWarning {
+    /// Column of the start of the offending part, starting at 1
+    pub fn column(&self) -> usize {
+        column(&self.line_text, &self.span)
+    }
+}
+
+impl fmt::Display for CueWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Diagnostic {
+            level: "warning",
+            message: &self.kind,
+            path: self.path.as_deref(),
+            line: self.line,
+            span: &self.span,
+            line_text: &self.line_text,
+        }.fmt(f)
+    }
+}
+
+// Rendering of a message about part of a line of a cuesheet, quoting the line
+// and marking the part below it
+struct Diagnostic<'a> {
+    level: &'a str,
+    message: &'a dyn fmt::Display,
+    path: Option<&'a Path>,
+    line: usize,
+    span: &'a Range<usize>,
+    line_text: &'a str,
+}
+
+impl fmt::Display for Diagnostic<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let gutter = " ".repeat(self.line.to_string().len());
+        writeln!(f, "{}: {}", self.level, self.message)?;
+        write!(f, "{}--> ", gutter)?;
+        if let Some(path) = self.path {
+            write!(f, "{}:", path.display())?;
+        }
+        writeln!(f, "{}:{}", self.line, column(self.line_text, self.span))?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", self.line, self.line_text)?;
+        // Keep tabs so the markers line up with the quoted line
+        let indent: String = self.line_text[..self.span.start].chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = self.line_text[self.span.clone()].chars().count().max(1);
+        write!(f, "{} | {}{}", gutter, indent, "^".repeat(width))
+    }
+}
+
+fn column(line_text: &str, span: &Range<usize>) -> usize {
+    line_text[..span.start].chars().count() + 1
+}
+
+// Byte ranges of the whitespace separated words in `line`
+fn word_spans(line: &str) -> Vec<Range<usize>> {
+    let mut spans = Vec::new();
+    let mut word_start = None;
+    for (i, c) in line.char_indices().chain(std::iter::once((line.len(), ' '))) {
+        match (c.is_whitespace(), word_start) {
+            (true, Some(start)) => {
+                spans.push(start..i);
+                word_start = None;
+            }
+            (false, None) => word_start = Some(i),
+            _ => {}
+        }
+    }
+    spans
+}
+
+// Guesses which part of `line` caused `error`: the offending word if the
+// error names one, the command if it is invalid or unexpected, the whole line
+// for errors about the FILE or TRACK it starts, and the arguments otherwise
+fn error_span(line: &str, error: &CueError) -> Range<usize> {
+    let start = line.len() - line.trim_start().len();
+    let end = line.trim_end().len().max(start);
+    let command_end = line[start..end].find(char::is_whitespace).map_or(end, |x| start + x);
+    let args_start = end - line[command_end..end].trim_start().len();
+
+    let word = match error {
+        CueError::UnknownFlag(flag) => {
+            line[args_start..end].find(flag.as_str()).map(|x| args_start + x..args_start + x + flag.len())
+        }
+        CueError::UnknownTrackType(word) | CueError::UnknownBinMode(word) => {
+            line[..end].to_ascii_uppercase().rfind(word.as_str()).map(|x| x..x + word.len())
+        }
+        _ => None,
+    };
+    match (word, error) {
+        (Some(span), _) => span,
+        (None, CueError::InvalidCommandError(_)) |
+        (None, CueError::TrackCommandWithoutBinFile) |
+        (None, CueError::IndexCommandWithoutTrack) |
+        (None, CueError::GapCommandWithoutTrack) |
+        (None, CueError::FlagsCommandWithoutTrack) => start..command_end,
+        (None, CueError::NoTracks) | (None, CueError::TrackWithoutIndex01) => start..end,
+        _ if args_start == end => start..command_end,
+        _ => args_start..end,
+    }
+}
+
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum BinMode {
@@ -84,13 +338,20 @@ impl BinMode {
 
 
 impl TrackType {
-    fn try_from_str(s: &str) -> Result<TrackType, CueError> {
+    // Also returns the format the track's sectors are stored in and their size
+    // in the bin file, which is larger than the format's data size for CDG
+    // tracks as they carry 96 bytes of subcode per sector.
+    fn try_from_str(s: &str) -> Result<(TrackType, SectorFormat, u32), CueError> {
         use self::TrackType::*;
         let s_uppercase = s.trim().to_uppercase();
         match s_uppercase.as_str() {
-            "AUDIO" => Ok(Audio),
-            "MODE1" => Ok(Mode1),
-            "MODE2" | "MODE2/2352" => Ok(Mode2),
+            "AUDIO" => Ok((Audio, SectorFormat::Raw, 2352)),
+            "CDG" => Ok((Audio, SectorFormat::Raw, 2448)),
+            "MODE1" | "MODE1/2352" => Ok((Mode1, SectorFormat::Raw, 2352)),
+            "MODE1/2048" => Ok((Mode1, SectorFormat::Mode1Cooked, 2048)),
+            "MODE2" | "MODE2/2352" => Ok((Mode2, SectorFormat::Raw, 2352)),
+            "MODE2/2336" => Ok((Mode2, SectorFormat::Mode2Cooked, 2336)),
+            "MODE2/2048" => Ok((Mode2, SectorFormat::Mode2Form1Cooked, 2048)),
             _ => Err(CueError::UnknownTrackType(s_uppercase.clone()))
         }
     }
@@ -99,12 +360,29 @@ impl TrackType {
 #[derive(Clone)]
 struct Track {
     track_type: TrackType,
+    flags: TrackFlags,
+    format: SectorFormat,
+    // Size of a sector in the bin file in bytes
+    sector_size: u32,
 
     // local to bin file
     starting_lba: u32,
+    // Offset of the sector at `starting_lba` in the bin file in bytes
+    byte_offset: u64,
 
+    // Number of sectors stored in the bin file
     num_sectors: u32,
 
+    // Number of sectors not present in the bin file that precede (PREGAP) or
+    // follow (POSTGAP) the track's data. The pregap of the first track
+    // includes the 150 sectors before 00:02:00.
+    pregap: u32,
+    postgap: u32,
+
+    // Absolute LBA of the first sector of the track on the disc, including
+    // the pregap
+    global_start: u32,
+
     // These are direct translations from the MSF timestamp given in the cue file,
     // so they are local to the corresponding bin file.
     // Note: Valid tracks always have an index 1.
@@ -112,6 +390,58 @@ struct Track {
 }
 
 impl Track {
+    // Offset of the sector at `bin_local_lba` in the bin file in bytes
+    fn sector_offset(&self, bin_local_lba: u32) -> u64 {
+        self.byte_offset + (bin_local_lba - self.starting_lba) as u64 * self.sector_size as u64
+    }
+
+    // Number of sectors of the track on the disc, including pre- and postgap
+    fn global_length(&self) -> u32 {
+        self.pregap + self.num_sectors + self.postgap
+    }
+
+    fn global_end(&self) -> u32 {
+        self.global_start + self.global_length()
+    }
+
+    // Absolute LBA of the given index. A pregap not present in the bin file
+    // counts as index 00.
+    fn global_index(&self, index: u8) -> Option<u32> {
+        if index == 0 && self.pregap > 0 {
+            return Some(self.global_start);
+        }
+        self.indices.get(index as usize)
+            .map(|lba| self.global_start + self.pregap + lba - self.starting_lba)
+    }
+
+    // Numbers of all indices of the track, including the pregap's index 00
+    fn index_numbers(&self) -> impl Iterator<Item = u8> + '_ {
+        let pregap_index = if self.pregap > 0 && !self.indices.contains_key(0) { Some(0) } else { None };
+        pregap_index.into_iter().chain(self.indices.keys().map(|x| x as u8))
+    }
+
+    fn global_index_one(&self) -> u32 {
+        self.global_index(1).unwrap()
+    }
+
+    fn index_for_lba(&self, global_lba: u32) -> u8 {
+        self.indices.keys()
+            .rev()
+            .find(|&index| self.global_index(index as u8).unwrap() <= global_lba)
+            .map_or(0, |index| index as u8)
+    }
+
+    // Translates an absolute LBA to the corresponding LBA in the bin file, or
+    // `None` if the sector lies in the pre- or postgap.
+    fn bin_local_lba(&self, global_lba: u32) -> Option<u32> {
+        let data_start = self.global_start + self.pregap;
+        if global_lba >= data_start && global_lba < data_start + self.num_sectors {
+            Some(self.starting_lba + global_lba - data_start)
+        } else {
+            None
+        }
+    }
+
     fn first_index_lba(&self) -> u32 {
         if let Some(lba) = self.indices.get(0) {
             *lba
@@ -121,69 +451,151 @@ impl Track {
     }
 }
 
+enum BinData {
+    Plain(Box<dyn ReadSeek>),
+    // FLAC files are read through the decoder
+    #[cfg(feature = "flac")]
+    Flac(flac_file::FlacFile),
+}
+
 struct BinFile {
-    file: File,
+    data: BinData,
     bin_mode: BinMode,
-    tracks: Vec<Track>
+    // Offset and length of the sector data in the file, which is only part of
+    // it for audio container formats
+    data_offset: u64,
+    data_len: u64,
+    // Whether the file holds big endian samples that need to be swapped
+    byte_swap: bool,
+    tracks: Vec<Track>,
 }
 
 impl BinFile {
-    fn num_sectors(&self) -> io::Result<u32> {
-        Ok(self.file.metadata()?.len() as u32 / 2352)
-    }
-
-    // Checks whether the given tracks are valid, calculates their lengths and
-    // adds them to the BinFile.
+    // Checks whether the BinFile has any tracks and calculates their lengths.
+    // The tracks need to have an index 01.
     fn finalize_tracks(&mut self) -> Result<(), CueError> {
         if self.tracks.is_empty() {
             return Err(CueError::NoTracks);
         }
         for i in 0..self.tracks.len() - 1 {
-            if !self.tracks[i].indices.contains_key(1) ||
-               !self.tracks[i + 1].indices.contains_key(1)
-            {
-                return Err(CueError::TrackWithoutIndex01);
-            }
             let length = self.tracks[i+1].first_index_lba() - self.tracks[i].first_index_lba();
             self.tracks[i].num_sectors = length;
             self.tracks[i + 1].starting_lba = self.tracks[i].starting_lba + length;
+            self.tracks[i + 1].byte_offset = self.tracks[i].byte_offset +
+                length as u64 * self.tracks[i].sector_size as u64;
         }
-        let bin_num_sectors = self.num_sectors()?;
         let last_track = self.tracks.last_mut().unwrap();
-        last_track.num_sectors = bin_num_sectors - last_track.first_index_lba();
+        let sector_size = last_track.sector_size as u64;
+        let remaining_len = self.data_len.saturating_sub(last_track.byte_offset);
+        last_track.num_sectors = if self.bin_mode == BinMode::Binary {
+            if !remaining_len.is_multiple_of(sector_size) {
+                warn!("Size of the last track's data is not a multiple of {} bytes.", sector_size);
+            }
+            (remaining_len / sector_size) as u32
+        } else {
+            // Audio files don't need to end on a sector boundary, the last
+            // sector is padded with silence.
+            remaining_len.div_ceil(sector_size) as u32
+        };
         Ok(())
     }
+
+    // Fills `buf` with the data at `offset` relative to the start of the
+    // sector data, padding it with zeros past the end of the data.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), CueError> {
+        match self.data {
+            BinData::Plain(ref mut file) => {
+                let available = self.data_len.saturating_sub(offset).min(buf.len() as u64) as usize;
+                file.seek(SeekFrom::Start(self.data_offset + offset))?;
+                file.read_exact(&mut buf[..available])?;
+                buf[available..].fill(0);
+                Ok(())
+            }
+            #[cfg(feature = "flac")]
+            BinData::Flac(ref mut flac) => flac.read_at(offset, buf),
+        }
+    }
 }
 
 pub struct Cuesheet {
     bin_files: Vec<BinFile>,
     location: Location,
-    invalid_subq_lbas: Option<BTreeSet<u32>>,
+    metadata: Metadata,
+    // From the file referenced by CDTEXTFILE
+    cd_text: Option<CdText>,
+    sidecars: Sidecars,
+    // Deinterleaved subchannel data of every sector from 00:02:00 on as
+    // written by CloneCD, and the number of sectors it covers
+    sub_file: Option<(Box<dyn ReadSeek>, u32)>,
 }
 
-fn parse_file_line(line: &str, cue_dir: Option<&Path>) -> Result<BinFile, CueError> {
-    let line = line.trim();
-    let quote_matches = line.match_indices("\"").collect::<Vec<_>>();
-    if quote_matches.len() != 2 {
-        return Err(CueError::FileNameParseError);
-    }
-    // Extract the filename surrounded by quotes
-    let bin_filename = &line[(quote_matches[0].0 + 1)..quote_matches[1].0];
-    let bin_mode_str = line.rsplit(|c: char| c.is_whitespace()).next().unwrap();
-
-    let file = if let Some(cue_dir) = cue_dir {
-        File::open(cue_dir.join(bin_filename))?
-    } else {
-        File::open(bin_filename)?
-    };
-    let len = file.metadata()?.len();
-    if len % 2352 != 0 {
-        warn!("Size of file \"{}\" is not a multiple of 2352 bytes.", bin_filename);
+// Opens a file referenced by a FILE command given its name
+type Resolver<'a> = dyn FnMut(&str) -> std::io::Result<Box<dyn ReadSeek>> + 'a;
+
+// Returns the byte range of the file name in a FILE line, which needs to be
+// enclosed in quotes. When parsing leniently, names without quotes, with
+// quotes in them or with whitespace around them are accepted with a warning.
+fn file_name_span(line: &str, lenient: bool) -> Result<(Range<usize>, Option<CueWarningKind>), CueError> {
+    let quotes = line.match_indices('"').map(|(i, _)| i).collect::<Vec<_>>();
+    match quotes.len() {
+        2 => {
+            let span = quotes[0] + 1..quotes[1];
+            let name = &line[span.clone()];
+            let trimmed = name.trim();
+            if lenient && !trimmed.is_empty() && trimmed.len() != name.len() {
+                let start = span.start + name.len() - name.trim_start().len();
+                return Ok((start..start + trimmed.len(), Some(CueWarningKind::WhitespaceAroundFileName)));
+            }
+            Ok((span, None))
+        }
+        n if n > 2 && lenient => Ok((quotes[0] + 1..quotes[n - 1], Some(CueWarningKind::QuotesInFileName))),
+        0 if lenient => {
+            // Everything between the command and the bin mode
+            let words = word_spans(line);
+            if words.len() < 3 {
+                return Err(CueError::FileNameParseError);
+            }
+            Ok((words[1].start..words[words.len() - 2].end, Some(CueWarningKind::UnquotedFileName)))
+        }
+        _ => Err(CueError::FileNameParseError),
+    }
+}
+
+fn parse_file_line(line: &str, bin_filename: &str, resolver: &mut Resolver) -> Result<BinFile, CueError> {
+    let bin_mode_str = line.trim().rsplit(|c: char| c.is_whitespace()).next().unwrap();
+
+    let mut file = resolver(bin_filename)?;
+    let bin_mode = BinMode::try_from_str(bin_mode_str)?;
+    #[cfg(feature = "flac")]
+    if bin_mode == BinMode::Wave && flac_file::is_flac(&mut file)? {
+        let flac = flac_file::FlacFile::open(file)?;
+        return Ok(BinFile {
+            bin_mode,
+            data_offset: 0,
+            data_len: flac.len(),
+            byte_swap: false,
+            data: BinData::Flac(flac),
+            tracks: Vec::new(),
+        });
     }
+    let (data_offset, data_len, byte_swap) = match bin_mode {
+        BinMode::Binary => (0, file.seek(SeekFrom::End(0))?, false),
+        BinMode::Motorola => (0, file.seek(SeekFrom::End(0))?, true),
+        BinMode::Wave | BinMode::Aiff => {
+            // Some cuesheets label AIFF files as WAVE and vice versa
+            let audio = audio_file::parse_wave(&mut file)
+                .or_else(|_| audio_file::parse_aiff(&mut file))?;
+            (audio.offset, audio.len, audio.big_endian)
+        }
+        BinMode::Mp3 => return Err(CueError::UnsupportedBinMode(bin_mode)),
+    };
     Ok(BinFile {
-        file,
-        bin_mode: BinMode::try_from_str(bin_mode_str)?,
-        tracks: Vec::new()
+        data: BinData::Plain(file),
+        bin_mode,
+        data_offset,
+        data_len,
+        byte_swap,
+        tracks: Vec::new(),
     })
 }
 
@@ -194,11 +606,21 @@ fn parse_track_line(line: &str) -> Result<(Track, u8), CueError> {
         return Err(CueError::InvalidTrackLine);
     }
     let track_number = line_elems[1].parse()?;
-    let track_type = TrackType::try_from_str(line_elems[2])?;
+    if !(1..=99).contains(&track_number) {
+        return Err(CueError::InvalidTrackNumber);
+    }
+    let (track_type, format, sector_size) = TrackType::try_from_str(line_elems[2])?;
     let track = Track {
         track_type,
+        flags: TrackFlags::empty(),
+        format,
+        sector_size,
         starting_lba: 0,
+        byte_offset: 0,
         num_sectors: 0,
+        pregap: 0,
+        postgap: 0,
+        global_start: 0,
         indices: VecMap::new(),
     };
     Ok((track, track_number))
@@ -215,17 +637,270 @@ fn parse_index_line(line: &str) -> Result<(u8, MsfIndex), CueError> {
     Ok((index_number, index))
 }
 
-#[allow(unused)]
-fn parse_pregap_line(line: &str) -> Result<MsfIndex, CueError> {
+// Parses a PREGAP or POSTGAP line, returning `err` if it is malformed
+fn parse_gap_line(line: &str, err: CueError) -> Result<MsfIndex, CueError> {
     let line = line.trim();
     let line_elems = line.split_whitespace().collect::<Vec<&str>>();
     if line_elems.len() != 2 {
-        return Err(CueError::InvalidPregapLine);
+        return Err(err);
     }
     let index = MsfIndex::try_from_str(line_elems[1])?;
     Ok(index)
 }
 
+fn parse_flags_line(line: &str) -> Result<TrackFlags, CueError> {
+    let mut flags = TrackFlags::empty();
+    for flag in line.split_whitespace().skip(1) {
+        flags |= match flag.to_uppercase().as_str() {
+            "PRE" => TrackFlags::PRE_EMPHASIS,
+            "DCP" => TrackFlags::COPY_PERMITTED,
+            "4CH" => TrackFlags::FOUR_CHANNEL,
+            "SCMS" => TrackFlags::SCMS,
+            _ => return Err(CueError::UnknownFlag(flag.to_string())),
+        };
+    }
+    Ok(flags)
+}
+
+// Loads the CD-TEXT data from the file referenced by a CDTEXTFILE line
+fn parse_cdtext_line(command: &str, line: &str, resolver: &mut Resolver) -> Result<CdText, CueError> {
+    let file_name = parse_string_arg(&line.trim_start()[command.len()..])
+        .ok_or(CueError::FileNameParseError)?;
+    let mut data = Vec::new();
+    resolver(&file_name)?.read_to_end(&mut data)?;
+    Ok(CdText::parse(&data)?)
+}
+
+// Parses the argument of a metadata command, which is either a quoted string
+// (where `\"` and `\\` are escapes) or the rest of the line
+fn parse_string_arg(arg: &str) -> Option<String> {
+    let arg = arg.trim();
+    let quoted = match arg.strip_prefix('"') {
+        Some(quoted) => quoted,
+        None if arg.is_empty() => return None,
+        None => return Some(arg.to_string()),
+    };
+    let mut result = String::new();
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some(result),
+            '\\' => match chars.next() {
+                Some(c @ ('"' | '\\')) => result.push(c),
+                Some(c) => {
+                    result.push('\\');
+                    result.push(c);
+                }
+                None => return None,
+            },
+            c => result.push(c),
+        }
+    }
+    None
+}
+
+// Parses a ReplayGain value like "-6.25 dB"
+fn parse_replay_gain_value(value: &str) -> Option<f32> {
+    let value = value.trim().to_lowercase();
+    value.strip_suffix("db").unwrap_or(&value).trim().parse().ok()
+}
+
+// Stores the information from a TITLE, PERFORMER, SONGWRITER, CATALOG, ISRC
+// or REM line in `metadata`. `in_track` tells whether the line belongs to the
+// last track in `metadata.tracks` instead of the whole disc.
+fn parse_metadata_line(command: &str, line: &str, metadata: &mut Metadata, in_track: bool) -> Result<(), CueError> {
+    let arg = &line.trim_start()[command.len()..];
+    let command = command.to_uppercase();
+    let track = if in_track { metadata.tracks.last_mut() } else { None };
+
+    if command == "REM" {
+        let mut arg = arg.trim_start().splitn(2, char::is_whitespace);
+        let key = match arg.next() {
+            Some(key) if !key.is_empty() => key.to_uppercase(),
+            _ => return Ok(()),
+        };
+        let value = arg.next().and_then(parse_string_arg).unwrap_or_default();
+        let replay_gain = match (key.as_str(), track) {
+            ("REPLAYGAIN_ALBUM_GAIN", _) => Some(&mut metadata.replay_gain.gain),
+            ("REPLAYGAIN_ALBUM_PEAK", _) => Some(&mut metadata.replay_gain.peak),
+            ("REPLAYGAIN_TRACK_GAIN", Some(track)) => Some(&mut track.replay_gain.gain),
+            ("REPLAYGAIN_TRACK_PEAK", Some(track)) => Some(&mut track.replay_gain.peak),
+            (_, Some(track)) => {
+                track.remarks.push((key, value));
+                return Ok(());
+            }
+            _ => None,
+        };
+        if let Some(replay_gain) = replay_gain {
+            *replay_gain = parse_replay_gain_value(&value);
+            if replay_gain.is_none() {
+                warn!("Invalid value {:?} for {} in cuesheet", value, key);
+            }
+            return Ok(());
+        }
+        let field = match key.as_str() {
+            "GENRE" => &mut metadata.genre,
+            "DATE" => &mut metadata.date,
+            "DISCID" => &mut metadata.disc_id,
+            "COMMENT" => &mut metadata.comment,
+            _ => {
+                metadata.remarks.push((key, value));
+                return Ok(());
+            }
+        };
+        *field = Some(value);
+        return Ok(());
+    }
+
+    let value = parse_string_arg(arg).ok_or_else(|| CueError::InvalidMetadataLine(command.clone()))?;
+    let field = match (command.as_str(), track) {
+        ("CATALOG", _) => &mut metadata.catalog,
+        ("TITLE", Some(track)) => &mut track.title,
+        ("PERFORMER", Some(track)) => &mut track.performer,
+        ("SONGWRITER", Some(track)) => &mut track.songwriter,
+        ("ISRC", Some(track)) => &mut track.isrc,
+        ("TITLE", None) => &mut metadata.title,
+        ("PERFORMER", None) => &mut metadata.performer,
+        ("SONGWRITER", None) => &mut metadata.songwriter,
+        _ => {
+            warn!("Ignoring {} command outside of a track", command);
+            return Ok(());
+        }
+    };
+    *field = Some(value);
+    Ok(())
+}
+
+// State of `Cuesheet::parse` between lines
+#[derive(Default)]
+struct ParseState<'a> {
+    cue: &'a str,
+    bin_files: Vec<BinFile>,
+    current_track_number: u8,
+    // Number of the last track as written in the cuesheet, which differs from
+    // `current_track_number` after renumbering a gap in lenient mode
+    written_track_number: u8,
+    // Along with the number of the line of their FILE or TRACK command
+    current_bin_file: Option<(BinFile, usize)>,
+    current_track: Option<(Track, usize)>,
+    tracks_for_current_bin_file: Vec<Track>,
+    metadata: Metadata,
+    cd_text: Option<CdText>,
+    lenient: bool,
+    warnings: Vec<CueWarning>,
+}
+
+impl ParseState<'_> {
+    fn parse_line(&mut self, command: &str, line: &str, line_no: usize, resolver: &mut Resolver)
+        -> Result<(), CueError>
+    {
+        let cmd_uppercase = command.to_uppercase();
+        match cmd_uppercase.as_str() {
+            "FILE" => {
+                self.end_bin_file()?;
+                let (name_span, warning) = file_name_span(line, self.lenient)?;
+                if let Some(warning) = warning {
+                    self.warn(line_no, name_span.clone(), warning);
+                }
+                let bin_file = parse_file_line(line, &line[name_span], resolver)?;
+                self.current_bin_file = Some((bin_file, line_no));
+            }
+            "TRACK" => {
+                if self.current_bin_file.is_none() {
+                    return Err(CueError::TrackCommandWithoutBinFile);
+                }
+                self.end_track()?;
+                let (track, written_number) = parse_track_line(line)?;
+                let expected = self.written_track_number + 1;
+                if written_number != expected {
+                    if !self.lenient {
+                        return Err(CueError::InvalidTrackNumber);
+                    }
+                    let warning = CueWarningKind::TrackNumberGap { expected, found: written_number };
+                    self.warn(line_no, word_spans(line)[1].clone(), warning);
+                }
+                self.written_track_number = written_number;
+                // Lenient mode may renumber tracks past the last valid number
+                if self.current_track_number >= 99 {
+                    return Err(CueError::InvalidTrackNumber);
+                }
+                let track_number = self.current_track_number + 1;
+                self.current_track_number = track_number;
+                self.current_track = Some((track, line_no));
+                self.metadata.tracks.push(TrackMetadata { number: track_number, ..Default::default() });
+            }
+            "PREGAP" => {
+                let track = self.track_mut(CueError::GapCommandWithoutTrack)?;
+                track.pregap = parse_gap_line(line, CueError::InvalidPregapLine)?.to_lba();
+            }
+            "POSTGAP" => {
+                let track = self.track_mut(CueError::GapCommandWithoutTrack)?;
+                track.postgap = parse_gap_line(line, CueError::InvalidPostgapLine)?.to_lba();
+            }
+            "INDEX" => {
+                let track = self.track_mut(CueError::IndexCommandWithoutTrack)?;
+                let (index_number, index) = parse_index_line(line)?;
+                if index_number == 0 {
+                    if track.indices.contains_key(0) {
+                        // INDEX 00 is also a type of pregap, so we have two
+                        // pregaps at this point.
+                        // FIXME: Maybe use a more descriptive error message?
+                        return Err(CueError::InvalidIndexNumber);
+                    }
+                    track.indices.insert(0, index.to_lba());
+                } else {
+                    track.indices.insert(index_number as usize, index.to_lba());
+                }
+            }
+            "FLAGS" => {
+                self.track_mut(CueError::FlagsCommandWithoutTrack)?.flags = parse_flags_line(line)?;
+            }
+            "CDTEXTFILE" => self.cd_text = Some(parse_cdtext_line(command, line, resolver)?),
+            "TITLE" | "PERFORMER" | "SONGWRITER" | "CATALOG" | "ISRC" | "REM" => {
+                parse_metadata_line(command, line, &mut self.metadata, self.current_track.is_some())?;
+            }
+            _ if self.lenient => {
+                self.warn(line_no, word_spans(line)[0].clone(), CueWarningKind::UnknownCommand(cmd_uppercase));
+            }
+            _ =>  {
+                return Err(CueError::InvalidCommandError(cmd_uppercase.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn warn(&mut self, line_no: usize, span: Range<usize>, kind: CueWarningKind) {
+        let line_text = self.cue.lines().nth(line_no - 1).unwrap_or_default().to_string();
+        self.warnings.push(CueWarning { path: None, line: line_no, span, line_text, kind });
+    }
+
+    // Returns the current track, or `err` if there is none
+    fn track_mut(&mut self, err: CueError) -> Result<&mut Track, CueError> {
+        self.current_track.as_mut().map(|(track, _)| track).ok_or(err)
+    }
+
+    // Adds the current track to the tracks of the current bin file
+    fn end_track(&mut self) -> Result<(), CueError> {
+        if let Some((track, line_no)) = self.current_track.take() {
+            if !track.indices.contains_key(1) {
+                return Err(CueError::TrackWithoutIndex01.at_line(self.cue, line_no));
+            }
+            self.tracks_for_current_bin_file.push(track);
+        }
+        Ok(())
+    }
+
+    fn end_bin_file(&mut self) -> Result<(), CueError> {
+        self.end_track()?;
+        if let Some((mut bin_file, line_no)) = self.current_bin_file.take() {
+            bin_file.tracks = std::mem::take(&mut self.tracks_for_current_bin_file);
+            bin_file.finalize_tracks().map_err(|e| e.at_line(self.cue, line_no))?;
+            self.bin_files.push(bin_file);
+        }
+        Ok(())
+    }
+}
+
 impl Cuesheet {
     pub fn open<P>(path: P) -> Result<Cuesheet, CueError>
         where P: AsRef<Path>
@@ -233,113 +908,156 @@ impl Cuesheet {
         Self::_open(path.as_ref())
     }
 
+    /// Like `open`, but loads the side-car files according to `options`.
+    pub fn open_with_options<P>(path: P, options: &OpenOptions) -> Result<Cuesheet, CueError>
+        where P: AsRef<Path>
+    {
+        let (cuesheet, warnings) = Self::open_with_options_impl(path.as_ref(), options, options.is_lenient())?;
+        for warning in warnings {
+            warn!("{}", warning);
+        }
+        Ok(cuesheet)
+    }
+
+    /// Like `open_with_options`, but accepts cuesheets deviating from the
+    /// format in the ways listed in `CueWarningKind` and returns a warning
+    /// for each deviation.
+    pub fn open_lenient<P>(path: P, options: &OpenOptions) -> Result<(Cuesheet, Vec<CueWarning>), CueError>
+        where P: AsRef<Path>
+    {
+        Self::open_with_options_impl(path.as_ref(), options, true)
+    }
+
     pub fn _open(path: &Path) -> Result<Cuesheet, CueError> {
+        Self::open_with_options(path, &OpenOptions::new())
+    }
+
+    fn open_with_options_impl(path: &Path, options: &OpenOptions, lenient: bool)
+        -> Result<(Cuesheet, Vec<CueWarning>), CueError>
+    {
         let mut cue_file = File::open(path)?;
         let mut cue_string = String::new();
         cue_file.read_to_string(&mut cue_string)?;
 
-        let mut bin_files: Vec<BinFile> = Vec::new();
-        let mut current_track_number = 0;
-        let mut current_bin_file: Option<BinFile> = None;
-        let mut current_track: Option<Track> = None;
-        let mut tracks_for_current_bin_file: Vec<Track> = Vec::new();
+        let cue_dir = path.parent();
+        let (mut cuesheet, mut warnings) = Self::parse(&cue_string, lenient, &mut |bin_filename| {
+            let file = if let Some(cue_dir) = cue_dir {
+                File::open(cue_dir.join(bin_filename))?
+            } else {
+                File::open(bin_filename)?
+            };
+            Ok(Box::new(file) as Box<dyn ReadSeek>)
+        }).map_err(|e| e.with_path(path))?;
+        for warning in &mut warnings {
+            warning.path = Some(path.to_path_buf());
+        }
 
-        for line in cue_string.lines() {
-            if let Some(command) = line.split_whitespace().next() {
-                let cmd_uppercase = command.to_uppercase();
-                match cmd_uppercase.as_str() {
-                    "FILE" => {
-                        if let Some(mut current_bin_file) = current_bin_file {
-                            if let Some(track) = current_track {
-                                tracks_for_current_bin_file.push(track);
-                            }
-                            current_track = None;
-                            current_bin_file.tracks = tracks_for_current_bin_file;
-                            tracks_for_current_bin_file = Vec::new();
-                            current_bin_file.finalize_tracks()?;
-                            bin_files.push(current_bin_file);
-                        }
-                        current_bin_file = Some(parse_file_line(line, path.parent())?);
-                        if current_bin_file.as_ref().unwrap().bin_mode != BinMode::Binary {
-                            warn!("No bin file modes apart from Binary supported yet.");
-                        }
-                    }
-                    "TRACK" => {
-                        if current_bin_file.is_some() {
-                            if let Some(track) = current_track {
-                                tracks_for_current_bin_file.push(track);
-                            }
-                            let (track, track_number) = parse_track_line(line)?;
-                            if track_number != current_track_number + 1 {
-                                return Err(CueError::InvalidTrackNumber);
-                            }
-                            current_track_number = track_number;
-                            current_track = Some(track);
-                        } else {
-                            return Err(CueError::TrackCommandWithoutBinFile);
-                        }
-                    }
-                    "PREGAP" => error!("Ignoring PREGAP command (not yet implemented)"),
-                    "INDEX" => {
-                        if let Some(ref mut track) = current_track {
-                            let (index_number, index) = parse_index_line(line)?;
-                            if index_number == 0 {
-                                if track.indices.contains_key(0) {
-                                    // INDEX 00 is also a type of pregap, so we have two
-                                    // pregaps at this point.
-                                    // FIXME: Maybe use a more descriptive error message?
-                                    return Err(CueError::InvalidIndexNumber);
-                                }
-                                track.indices.insert(0, index.to_lba());
-                            } else {
-                                track.indices.insert(index_number as usize, index.to_lba());
-                            }
-                        } else {
-                            return Err(CueError::IndexCommandWithoutTrack);
-                        }
-                    }
-                    "FLAGS" | "CDTEXTFILE" | "CATALOG" | "PERFORMER" | "TITLE" | "ISRC" => {} // TODO
-                    "REM" => {
-                        // TODO: Get ReplayGain information if present
-                    }
-                    _ =>  {
-                        return Err(CueError::InvalidCommandError(cmd_uppercase.clone()));
-                    }
-                }
+        cuesheet.sidecars = options.load_sidecars(path)?;
+
+        if let Some(sub_path) = options.detected_sidecar(path, "sub") {
+            let result = File::open(&sub_path).and_then(|file| {
+                let num_sectors = (file.metadata()?.len() / 96) as u32;
+                Ok((file, num_sectors))
+            });
+            let sub = cuesheet.sidecars.add(result, SidecarKind::Sub, sub_path, options.is_strict())?;
+            if let Some((sub_file, num_sectors)) = sub {
+                info!("Found subchannel data for {} sectors", num_sectors);
+                cuesheet.sub_file = Some((Box::new(sub_file), num_sectors));
             }
         }
-        if let Some(mut last_bin_file) = current_bin_file {
-            if let Some(last_track) = current_track {
-                tracks_for_current_bin_file.push(last_track);
-            } else {
-                return Err(CueError::NoTracks);
+
+        Ok((cuesheet, warnings))
+    }
+
+    /// Parses the cuesheet in `cue`, calling `resolver` with the name given in
+    /// each FILE command to open the referenced file. This allows reading
+    /// images from sources other than the file system.
+    pub fn from_str_with_resolver<F>(cue: &str, mut resolver: F) -> Result<Cuesheet, CueError>
+        where F: FnMut(&str) -> std::io::Result<Box<dyn ReadSeek>>
+    {
+        Self::parse(cue, false, &mut resolver).map(|(cuesheet, _)| cuesheet)
+    }
+
+    /// Like `from_str_with_resolver`, but parses leniently like `open_lenient`.
+    pub fn from_str_lenient_with_resolver<F>(cue: &str, mut resolver: F)
+        -> Result<(Cuesheet, Vec<CueWarning>), CueError>
+        where F: FnMut(&str) -> std::io::Result<Box<dyn ReadSeek>>
+    {
+        Self::parse(cue, true, &mut resolver)
+    }
+
+    fn parse(cue_string: &str, lenient: bool, resolver: &mut Resolver)
+        -> Result<(Cuesheet, Vec<CueWarning>), CueError>
+    {
+        let mut state = ParseState { cue: cue_string, lenient, ..Default::default() };
+        if let (true, Some(rest)) = (lenient, cue_string.strip_prefix('\u{feff}')) {
+            state.cue = rest;
+            state.warn(1, 0..0, CueWarningKind::ByteOrderMark);
+        }
+        let cue_string = state.cue;
+
+        for (i, line) in cue_string.lines().enumerate() {
+            if let Some(command) = line.split_whitespace().next() {
+                state.parse_line(command, line, i + 1, resolver)
+                    .map_err(|e| e.at_line(cue_string, i + 1))?;
             }
-            last_bin_file.tracks = tracks_for_current_bin_file;
-            last_bin_file.finalize_tracks()?;
-            bin_files.push(last_bin_file);
-        } else {
+        }
+        if state.current_bin_file.is_none() {
             return Err(CueError::NoBinFiles);
         }
+        state.end_bin_file()?;
 
-        let sbi_path = path.with_extension("sbi");
-        let mut invalid_subq_lbas = None;
-        if sbi_path.exists() {
-            match crate::sbi::load_sbi_file(sbi_path) {
-                Ok(set) => {
-                    info!("Found and loaded SBI file");
-                    invalid_subq_lbas = Some(set);
-                }
-                Err(e) => warn!("Failed to load SBI file: {}", e),
+        let ParseState { mut bin_files, metadata, cd_text, warnings, .. } = state;
+
+        // Lay out the tracks on the disc
+        let mut global_lba = 0;
+        for track in bin_files.iter_mut().flat_map(|x| x.tracks.iter_mut()) {
+            if global_lba == 0 {
+                track.pregap += 150;
             }
+            track.global_start = global_lba;
+            global_lba += track.global_length();
         }
 
-        Ok(Cuesheet{
+        let mut cuesheet = Cuesheet {
             bin_files,
             location: Location::default(),
-            invalid_subq_lbas,
-        })
+            metadata,
+            cd_text,
+            sidecars: Sidecars::default(),
+            sub_file: None,
+        };
+        cuesheet.location.global_lba = cuesheet.bin_files[0].tracks[0].global_index_one();
+        Ok((cuesheet, warnings))
+    }
+
+    fn locate(&self, global_lba: u32) -> Option<Location> {
+        // Most lookups are for the current track
+        let current = self.current_track_ref();
+        if (current.global_start..current.global_end()).contains(&global_lba) {
+            return Some(Location { global_lba, ..self.location });
+        }
+        for (bin_file_no, bin_file) in self.bin_files.iter().enumerate() {
+            for (track_in_bin, track) in bin_file.tracks.iter().enumerate() {
+                if global_lba < track.global_end() {
+                    return Some(Location {
+                        bin_file_no,
+                        track_in_bin,
+                        global_lba,
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    fn lead_out_lba(&self) -> u32 {
+        self.bin_files.last().unwrap().tracks.last().unwrap().global_end()
     }
 
+    fn current_track_ref(&self) -> &Track {
+        &self.bin_files[self.location.bin_file_no].tracks[self.location.track_in_bin]
+    }
 }
 
 impl Image for Cuesheet {
@@ -347,12 +1065,24 @@ impl Image for Cuesheet {
         std::iter::Sum::sum(self.bin_files.iter().map(|x| x.tracks.len()))
     }
 
-    fn current_subchannel_q_valid(&self) -> bool {
-        if let Some(ref invalid_subq_lbas) = self.invalid_subq_lbas {
-            !invalid_subq_lbas.contains(&self.location.global_lba)
-        } else {
-            true
-        }
+    fn sbi_records(&self) -> Option<&SbiRecords> {
+        self.sidecars.sbi_records.as_ref()
+    }
+
+    fn sidecars(&self) -> &[Sidecar] {
+        &self.sidecars.loaded
+    }
+
+    fn failed_sidecars(&self) -> &[(Sidecar, String)] {
+        &self.sidecars.failed
+    }
+
+    fn metadata(&self) -> Option<&Metadata> {
+        Some(&self.metadata)
+    }
+
+    fn cd_text(&self) -> Option<&CdText> {
+        self.cd_text.as_ref().or(self.sidecars.cd_text.as_ref())
     }
 
     fn current_track(&self) -> Result<u8, ImageError> {
@@ -364,36 +1094,21 @@ impl Image for Cuesheet {
         Ok(track_no + 1)
     }
 
-    // TODO: Currently only returns 0 or 1
     fn current_index(&self) -> Result<u8, ImageError> {
-        let index_one = *self.bin_files[self.location.bin_file_no]
-                            .tracks[self.location.track_in_bin]
-                            .indices.get(1).unwrap();
-        if self.location.bin_local_lba >= index_one {
-            Ok(1)
-        } else {
-            Ok(0)
-        }
+        Ok(self.current_track_ref().index_for_lba(self.location.global_lba))
     }
 
     fn current_track_local_msf(&self) -> Result<MsfIndex, ImageError> {
-        let start_of_track = self.bin_files[self.location.bin_file_no]
-                                    .tracks[self.location.track_in_bin]
-                                    .starting_lba;
-        let index_one = *self.bin_files[self.location.bin_file_no]
-                            .tracks[self.location.track_in_bin]
-                            .indices.get(1).unwrap() - start_of_track;
-        debug!("current_track_local_msf: \
-                start_of_track: {:?}, index_one: {:?}, loc.local_lba: {}",
-                start_of_track, index_one, self.location.bin_local_lba);
-        let track_local = self.location.bin_local_lba - start_of_track;
-        if track_local < index_one {
+        let index_one = self.current_track_ref().global_index_one();
+        debug!("current_track_local_msf: index_one: {}, global_lba: {}",
+               index_one, self.location.global_lba);
+        if self.location.global_lba < index_one {
             // Negative MSFs are (100,0,0) - x
             let reference = 100 * 60 * 75;
-            let offset = index_one - track_local;
+            let offset = index_one - self.location.global_lba;
             Ok(MsfIndex::from_lba(reference - offset)?)
         } else {
-            Ok(MsfIndex::from_lba(track_local - index_one)?)
+            Ok(MsfIndex::from_lba(self.location.global_lba - index_one)?)
         }
     }
 
@@ -404,9 +1119,7 @@ impl Image for Cuesheet {
     }
 
     fn current_track_type(&self) -> Result<TrackType, ImageError> {
-        Ok(self.bin_files[self.location.bin_file_no]
-               .tracks[self.location.track_in_bin]
-               .track_type)
+        Ok(self.current_track_ref().track_type)
     }
 
     fn first_track_type(&self) -> TrackType {
@@ -414,77 +1127,61 @@ impl Image for Cuesheet {
     }
 
     fn track_start(&self, track: u8) -> Result<MsfIndex, ImageError> {
-        // Track 0: Special case for PlayStation, return length of whole disc
-        // TODO: Make this less ugly?
-        if track == 0 {
-            // 150: Pregap of first track, not included in image
-            let mut len = 150;
-            for bin_file in self.bin_files.iter() {
-                len += bin_file.num_sectors()?;
-            }
-            return Ok(MsfIndex::from_lba(len)?);
-        }
-        let mut bin_pos_on_disc = 0;
-        let mut tracks_skipped = 0;
-        // Find correct bin file
-        for bin in self.bin_files.iter() {
-            if bin.tracks.len() >= (track - tracks_skipped) as usize {
-                let track_in_bin = track as usize - tracks_skipped as usize - 1;
+        let track = self.bin_files.iter()
+            .flat_map(|x| x.tracks.iter())
+            .nth((track as usize).wrapping_sub(1))
+            .ok_or(ImageError::OutOfRange)?;
+        Ok(MsfIndex::from_lba(track.global_index_one())?)
+    }
 
-                let track_index_one = bin.tracks[track_in_bin].indices.get(1).unwrap();
+    fn track_index_start(&self, track: u8, index: u8) -> Result<MsfIndex, ImageError> {
+        let lba = self.bin_files.iter()
+            .flat_map(|x| x.tracks.iter())
+            .nth((track as usize).wrapping_sub(1))
+            .and_then(|x| x.global_index(index))
+            .ok_or(ImageError::OutOfRange)?;
+        Ok(MsfIndex::from_lba(lba)?)
+    }
 
-                // HACK! I think we need to add the real amount of pregaps here
-                let pos_on_disc = bin_pos_on_disc + *track_index_one + 150;
+    fn lead_out(&self) -> Result<MsfIndex, ImageError> {
+        Ok(MsfIndex::from_lba(self.lead_out_lba())?)
+    }
 
-                return Ok(MsfIndex::from_lba(pos_on_disc)?);
-            } else {
-                tracks_skipped += bin.tracks.len() as u8;
-                bin_pos_on_disc = bin_pos_on_disc + bin.num_sectors()?;
-            }
+    fn toc(&self) -> Result<Toc, ImageError> {
+        let mut tracks = Vec::new();
+        for (i, track) in self.bin_files.iter().flat_map(|x| x.tracks.iter()).enumerate() {
+            let index_one = track.global_index_one();
+            let indices = track.index_numbers()
+                .map(|index| Ok((index, MsfIndex::from_lba(track.global_index(index).unwrap())?)))
+                .collect::<Result<Vec<_>, ImageError>>()?;
+            tracks.push(TrackInfo {
+                number: i as u8 + 1,
+                session: 1,
+                track_type: track.track_type,
+                flags: track.flags,
+                control: track.flags.control(track.track_type),
+                start: MsfIndex::from_lba(index_one)?,
+                length: track.global_end() - index_one,
+                indices,
+                pregap: index_one - track.global_start,
+                postgap: track.postgap,
+            });
         }
-        Err(ImageError::OutOfRange)
+        Ok(Toc::from_tracks(tracks, &[self.lead_out_lba()])?)
     }
 
-    // TODO: Change error type
-    fn set_location(&mut self, target: MsfIndex) -> Result<(), ImageError> {
-        // TODO: Subtract all pregaps not present in the bin files
-        let target_lba = target.to_lba();
-
-        // Hack for pregap of first track
-        if target_lba < 150 {
-            self.location = Location {
-                bin_file_no: 0,
-                track_in_bin: 0,
-                global_lba: target_lba,
-                bin_local_lba: 0,
-            };
-            return Ok(());
-        }
+    fn track_flags(&self, track: u8) -> Result<TrackFlags, ImageError> {
+        let track = self.bin_files.iter()
+            .flat_map(|x| x.tracks.iter())
+            .nth((track as usize).wrapping_sub(1))
+            .ok_or(ImageError::OutOfRange)?;
+        Ok(track.flags)
+    }
 
-        let mut current_lba_left = target_lba - 150;
-        for (bin_file_no, bin_file) in self.bin_files.iter().enumerate() {
-            let num_sectors_bin = bin_file.num_sectors()?;
-            if num_sectors_bin > current_lba_left {
-                let bin_offset = current_lba_left;
-                for (track_no, track) in bin_file.tracks.iter().enumerate() {
-                    if track.num_sectors > current_lba_left {
-                        self.location = Location {
-                            bin_file_no,
-                            track_in_bin: track_no,
-                            global_lba: target_lba,
-                            bin_local_lba: bin_offset,
-                        };
-                        debug!("set_location {:?}, result: {:?}", target, self.location);
-                        return Ok(());
-                    } else {
-                        current_lba_left -= track.num_sectors;
-                    }
-                }
-            } else {
-                current_lba_left -= num_sectors_bin;
-            }
-        }
-        Err(ImageError::OutOfRange)
+    fn set_location(&mut self, target: MsfIndex) -> Result<(), ImageError> {
+        self.location = self.locate(target.to_lba()).ok_or(ImageError::OutOfRange)?;
+        debug!("set_location {:?}, result: {:?}", target, self.location);
+        Ok(())
     }
 
     fn set_location_to_track(&mut self, track: u8) -> Result<(), ImageError> {
@@ -494,68 +1191,604 @@ impl Image for Cuesheet {
     }
 
     fn advance_position(&mut self) -> Result<Option<Event>, ImageError> {
-        if self.location.global_lba < 150 {
-            // Just doing this should be okay as the correct bin file,
-            // track and local LBA are selected for starting to read at sector
-            // (0,2,0) when seeking to the first track's pregap in `set_location()`.
-            self.location.global_lba += 1;
-            return Ok(None);
-        }
-        let bin_file = &self.bin_files[self.location.bin_file_no];
-        let track = &bin_file.tracks[self.location.track_in_bin];
-        let track_end = track.starting_lba + track.num_sectors;
+        let track = &self.bin_files[self.location.bin_file_no].tracks[self.location.track_in_bin];
+        let track_end = track.global_end();
+        let old_index = track.index_for_lba(self.location.global_lba);
         self.location.global_lba += 1;
-        self.location.bin_local_lba += 1;
-        if self.location.bin_local_lba < track_end {
-            Ok(None)
-        } else {
-            if bin_file.tracks.len() > self.location.track_in_bin + 1 {
-                self.location.track_in_bin += 1;
-                Ok(Some(Event::TrackChange))
-            } else if self.bin_files.len() > self.location.bin_file_no + 1 {
-                self.location.bin_file_no += 1;
-                self.location.track_in_bin = 0;
-                self.location.bin_local_lba = 0;
-                Ok(Some(Event::TrackChange))
+        if self.location.global_lba < track_end {
+            if track.index_for_lba(self.location.global_lba) != old_index {
+                Ok(Some(Event::IndexChange))
             } else {
-                Ok(Some(Event::EndOfDisc))
+                Ok(None)
             }
+        } else if self.bin_files[self.location.bin_file_no].tracks.len() > self.location.track_in_bin + 1 {
+            self.location.track_in_bin += 1;
+            Ok(Some(Event::TrackChange))
+        } else if self.bin_files.len() > self.location.bin_file_no + 1 {
+            self.location.bin_file_no += 1;
+            self.location.track_in_bin = 0;
+            Ok(Some(Event::TrackChange))
+        } else {
+            Ok(Some(Event::EndOfDisc))
         }
         // TODO start reading sector asynchronously
     }
 
     // `buf` needs to be 2352 bytes long.
     fn copy_current_sector(&mut self, buf: &mut [u8]) -> Result<(), ImageError> {
-        debug!("Reading sector {}, local {}", self.location.global_lba, self.location.bin_local_lba);
-        if self.location.global_lba < 150 {
-            for x in buf.iter_mut() {
-                *x = 0;
+        self.read_sector_at(self.location.global_lba, buf)
+    }
+
+    fn copy_current_subchannel_unpatched(&mut self, buf: &mut [u8; 96]) -> Result<(), ImageError> {
+        subchannel::synthesize(&*self, buf)?;
+        let lba = self.location.global_lba;
+        let bin_file = &mut self.bin_files[self.location.bin_file_no];
+        let track = &bin_file.tracks[self.location.track_in_bin];
+        // CDG tracks store the raw subcode after each sector, only R-W are
+        // used
+        let cdg_lba = track.bin_local_lba(lba)
+            .filter(|_| track.sector_size as usize == track.format.data_size() + 96);
+        if let Some(bin_local_lba) = cdg_lba {
+            let offset = track.sector_offset(bin_local_lba) + track.format.data_size() as u64;
+            let mut stored = [0u8; 96];
+            bin_file.read_at(offset, &mut stored)?;
+            subchannel::merge_rw(buf, &stored);
+        } else if let Some((ref mut sub_file, num_sectors)) = self.sub_file {
+            if lba >= 150 && lba - 150 < num_sectors {
+                let mut stored = [0u8; 96];
+                sub_file.seek(SeekFrom::Start((lba - 150) as u64 * 96))?;
+                sub_file.read_exact(&mut stored)?;
+                subchannel::interleave(&stored, buf);
             }
-            return Ok(());
         }
-        let mut file = &self.bin_files[self.location.bin_file_no].file;
-        file.seek(SeekFrom::Start(self.location.bin_local_lba as u64 * 2352))?;
-        file.read_exact(buf)?;
+        Ok(())
+    }
+
+    fn read_sector_at(&mut self, lba: u32, buf: &mut [u8]) -> Result<(), ImageError> {
+        let location = self.locate(lba).ok_or(ImageError::OutOfRange)?;
+        let bin_file = &mut self.bin_files[location.bin_file_no];
+        let track = &bin_file.tracks[location.track_in_bin];
+        let format = track.format;
+        let bin_local_lba = track.bin_local_lba(lba);
+        debug!("Reading sector {}, local {:?}", lba, bin_local_lba);
+        let offset = match bin_local_lba {
+            Some(lba) => track.sector_offset(lba),
+            None => {
+                // Pre- and postgaps not present in the bin file
+                buf.fill(0);
+                return Ok(());
+            }
+        };
+        let mut data = [0u8; 2352];
+        let data = &mut data[..format.data_size()];
+        bin_file.read_at(offset, data)?;
+        if bin_file.byte_swap {
+            for sample in data.chunks_exact_mut(2) {
+                sample.swap(0, 1);
+            }
+        }
+        format.expand(lba, data, buf);
         Ok(())
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Default)]
 struct Location {
     bin_file_no: usize,
     track_in_bin: usize,
     global_lba: u32,
-    bin_local_lba: u32,
 }
 
-impl Default for Location {
-    // Use first sector after the first track's pregap as the default
-    fn default() -> Location {
-        Location {
-            bin_file_no: 0,
-            track_in_bin: 0,
-            global_lba: 150,
-            bin_local_lba: 0
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::cue::*;
+
+    fn write_files(dir: &Path, cue: &str, bins: &[(&str, &[u8])]) -> PathBuf {
+        for (name, data) in bins {
+            std::fs::write(dir.join(name), data).unwrap();
         }
+        let cue_path = dir.join("test.cue");
+        std::fs::write(&cue_path, cue).unwrap();
+        cue_path
+    }
+
+    #[test]
+    fn cooked_mode1_track() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = [[1u8; 2048], [2u8; 2048]].concat();
+        let audio = [[3u8; 2352], [4u8; 2352], [5u8; 2352]].concat();
+        let cue_path = write_files(dir.path(), "\
+FILE \"data.bin\" BINARY
+  TRACK 01 MODE1/2048
+    INDEX 01 00:00:00
+FILE \"audio.bin\" BINARY
+  TRACK 02 AUDIO
+    INDEX 00 00:00:00
+    INDEX 01 00:00:01
+", &[("data.bin", &data), ("audio.bin", &audio)]);
+
+        let mut cue = Cuesheet::open(cue_path).unwrap();
+        assert_eq!(cue.num_tracks(), 2);
+        assert_eq!(cue.track_start(2).unwrap(), MsfIndex::from_lba(153).unwrap());
+
+        let mut buf = [0u8; 2352];
+        cue.set_location(MsfIndex::from_lba(151).unwrap()).unwrap();
+        cue.copy_current_sector(&mut buf).unwrap();
+        assert_eq!(&buf[12..16], &[0x00, 0x02, 0x01, 0x01]);
+        assert!(buf[16..2064].iter().all(|&x| x == 2));
+
+        assert_eq!(cue.advance_position().unwrap(), Some(Event::TrackChange));
+        cue.copy_current_sector(&mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 3));
+    }
+
+    #[test]
+    fn mode2_and_cdg_tracks() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = [
+            vec![1u8; 2336], vec![2u8; 2336],
+            vec![3u8; 2352], vec![4u8; 96], vec![5u8; 2352], vec![6u8; 96],
+        ].concat();
+        let cue_path = write_files(dir.path(), "\
+FILE \"test.bin\" BINARY
+  TRACK 01 MODE2/2336
+    INDEX 01 00:00:00
+  TRACK 02 CDG
+    INDEX 01 00:00:02
+", &[("test.bin", &bin)]);
+
+        let mut cue = Cuesheet::open(cue_path).unwrap();
+        assert_eq!(cue.lead_out().unwrap(), MsfIndex::from_lba(154).unwrap());
+
+        let mut buf = [0u8; 2352];
+        let mut subcode = [0u8; 96];
+        cue.set_location(MsfIndex::from_lba(151).unwrap()).unwrap();
+        cue.copy_current_sector(&mut buf).unwrap();
+        assert_eq!(&buf[12..16], &[0x00, 0x02, 0x01, 0x02]);
+        assert!(buf[16..].iter().all(|&x| x == 2));
+        // Nothing stored for this track, so P and Q are synthesized
+        cue.copy_current_subchannel_deinterleaved(&mut subcode).unwrap();
+        assert!(subcode[..12].iter().all(|&x| x == 0));
+        assert_eq!(&subcode[12..22], &[0x41, 0x01, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x02, 0x01]);
+
+        cue.advance_position().unwrap();
+        cue.advance_position().unwrap();
+        assert_eq!(cue.current_track_type().unwrap(), TrackType::Audio);
+        cue.copy_current_sector(&mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 5));
+        cue.copy_current_subchannel(&mut subcode).unwrap();
+        assert!(subcode.iter().all(|&x| x & 0x3f == 6));
+        cue.copy_current_subchannel_deinterleaved(&mut subcode).unwrap();
+        assert_eq!(&subcode[12..15], &[0x01, 0x02, 0x01]);
+    }
+
+    #[test]
+    fn subchannel_q_with_sbi() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = [[1u8; 2352], [2u8; 2352]].concat();
+        let mut sbi = b"SBI\0".to_vec();
+        sbi.extend([0x00, 0x02, 0x01, 0x01, 0x01, 0x01, 0x01, 0x00, 0x00, 0x11, 0x00, 0x00, 0x02, 0x01]);
+        let cue_path = write_files(dir.path(), "\
+FILE \"test.bin\" BINARY
+  TRACK 01 AUDIO
+    INDEX 01 00:00:00
+", &[("test.bin", &bin), ("test.sbi", &sbi)]);
+
+        let mut cue = Cuesheet::open(cue_path).unwrap();
+        let q = cue.current_subchannel_q().unwrap();
+        assert!(cue.current_subchannel_q_valid());
+        assert_eq!(&q[..10], &[0x01, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00]);
+        assert_eq!(u16::from_be_bytes([q[10], q[11]]), crate::subchannel::q_crc(&q[..10]));
+
+        cue.advance_position().unwrap();
+        let q = cue.current_subchannel_q().unwrap();
+        assert!(!cue.current_subchannel_q_valid());
+        assert_eq!(&q[..10], &sbi[8..18]);
+        assert_eq!(cue.sbi_records().unwrap().len(), 1);
+        assert_eq!(cue.sbi_record(151).unwrap().data(), &sbi[8..18]);
+    }
+
+    #[test]
+    fn sub_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = [[1u8; 2352], [2u8; 2352]].concat();
+        let cue_path = write_files(dir.path(), "\
+FILE \"test.bin\" BINARY
+  TRACK 01 AUDIO
+    INDEX 01 00:00:00
+", &[("test.bin", &bin), ("test.sub", &[0xa5; 96])]);
+
+        let mut cue = Cuesheet::open(cue_path).unwrap();
+        let mut subcode = [0u8; 96];
+        cue.copy_current_subchannel_deinterleaved(&mut subcode).unwrap();
+        assert!(subcode.iter().all(|&x| x == 0xa5));
+
+        // Not covered by the .sub file
+        cue.advance_position().unwrap();
+        cue.copy_current_subchannel_deinterleaved(&mut subcode).unwrap();
+        assert_eq!(&subcode[12..22], &[0x01, 0x01, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x02, 0x01]);
+    }
+
+    #[test]
+    fn pregap_and_postgap() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = [[1u8; 2352], [2u8; 2352], [3u8; 2352]].concat();
+        let cue_path = write_files(dir.path(), "\
+FILE \"test.bin\" BINARY
+  TRACK 01 MODE1/2352
+    INDEX 01 00:00:00
+    POSTGAP 00:00:02
+  TRACK 02 AUDIO
+    PREGAP 00:00:03
+    INDEX 01 00:00:01
+", &[("test.bin", &bin)]);
+
+        let mut cue = Cuesheet::open(cue_path).unwrap();
+        assert_eq!(cue.track_start(2).unwrap(), MsfIndex::from_lba(156).unwrap());
+        assert_eq!(cue.lead_out().unwrap(), MsfIndex::from_lba(158).unwrap());
+
+        let toc = cue.toc().unwrap();
+        assert_eq!((toc.first_track, toc.last_track), (1, 2));
+        assert_eq!(toc.lead_out, MsfIndex::from_lba(158).unwrap());
+        let track = toc.track(1).unwrap();
+        assert_eq!((track.control, track.pregap, track.length, track.postgap), (0x04, 150, 3, 2));
+        let track = toc.track(2).unwrap();
+        assert_eq!(track.indices, vec![(0, MsfIndex::from_lba(153).unwrap()), (1, MsfIndex::from_lba(156).unwrap())]);
+        assert_eq!((track.control, track.pregap, track.length, track.postgap), (0, 3, 2, 0));
+
+        let mut buf = [0u8; 2352];
+        cue.copy_current_sector(&mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 1));
+
+        // Postgap of track 1
+        cue.advance_position().unwrap();
+        cue.copy_current_sector(&mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 0));
+
+        assert_eq!(cue.advance_position().unwrap(), None);
+        assert_eq!(cue.advance_position().unwrap(), Some(Event::TrackChange));
+        assert_eq!(cue.current_index().unwrap(), 0);
+        assert_eq!(cue.current_track_local_msf().unwrap(), MsfIndex::new(99, 59, 72).unwrap());
+        cue.copy_current_sector(&mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 0));
+
+        cue.set_location(MsfIndex::from_lba(156).unwrap()).unwrap();
+        assert_eq!(cue.current_track().unwrap(), 2);
+        assert_eq!(cue.current_index().unwrap(), 1);
+        cue.copy_current_sector(&mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 2));
+        assert_eq!(cue.advance_position().unwrap(), None);
+        assert_eq!(cue.advance_position().unwrap(), Some(Event::EndOfDisc));
+    }
+
+    #[test]
+    fn multiple_indices() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = [[1u8; 2352], [2u8; 2352], [3u8; 2352], [4u8; 2352]].concat();
+        let cue_path = write_files(dir.path(), "\
+FILE \"test.bin\" BINARY
+  TRACK 01 AUDIO
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    INDEX 00 00:00:01
+    INDEX 01 00:00:02
+    INDEX 02 00:00:03
+", &[("test.bin", &bin)]);
+
+        let mut cue = Cuesheet::open(cue_path).unwrap();
+        assert_eq!(cue.track_index_start(1, 0).unwrap(), MsfIndex::from_lba(0).unwrap());
+        assert_eq!(cue.track_index_start(2, 2).unwrap(), MsfIndex::from_lba(153).unwrap());
+        assert!(cue.track_index_start(2, 3).is_err());
+        assert!(cue.track_index_start(3, 1).is_err());
+
+        assert_eq!(cue.advance_position().unwrap(), Some(Event::TrackChange));
+        assert_eq!(cue.current_index().unwrap(), 0);
+        assert_eq!(cue.advance_position().unwrap(), Some(Event::IndexChange));
+        assert_eq!(cue.current_index().unwrap(), 1);
+        assert_eq!(cue.advance_position().unwrap(), Some(Event::IndexChange));
+        assert_eq!(cue.current_index().unwrap(), 2);
+
+        cue.set_location_to_track_index(2, 1).unwrap();
+        assert_eq!(cue.current_track().unwrap(), 2);
+        assert_eq!(cue.current_index().unwrap(), 1);
+        let mut buf = [0u8; 2352];
+        cue.copy_current_sector(&mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 3));
+    }
+
+    #[test]
+    fn wave_and_motorola_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wave = b"RIFF\0\0\0\0WAVE".to_vec();
+        wave.extend(b"fmt \x10\0\0\0\x01\0\x02\0\x44\xac\0\0\x10\xb1\x02\0\x04\0\x10\0");
+        wave.extend(b"data");
+        wave.extend(2356u32.to_le_bytes());
+        wave.extend([1u8; 2352 + 4]);
+        let motorola: Vec<u8> = [0x12u8, 0x34].repeat(2352 / 2);
+        let cue_path = write_files(dir.path(), "\
+FILE \"test.wav\" WAVE
+  TRACK 01 AUDIO
+    INDEX 01 00:00:00
+FILE \"test.raw\" MOTOROLA
+  TRACK 02 AUDIO
+    INDEX 01 00:00:00
+", &[("test.wav", &wave), ("test.raw", &motorola)]);
+
+        let mut cue = Cuesheet::open(cue_path).unwrap();
+        assert_eq!(cue.track_start(2).unwrap(), MsfIndex::from_lba(152).unwrap());
+
+        let mut buf = [0u8; 2352];
+        cue.set_location(MsfIndex::from_lba(151).unwrap()).unwrap();
+        cue.copy_current_sector(&mut buf).unwrap();
+        assert!(buf[..4].iter().all(|&x| x == 1));
+        assert!(buf[4..].iter().all(|&x| x == 0));
+
+        cue.advance_position().unwrap();
+        cue.copy_current_sector(&mut buf).unwrap();
+        assert_eq!(&buf[..4], &[0x34, 0x12, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn in_memory_resolver() {
+        let cue = "\
+FILE \"a.bin\" BINARY
+  TRACK 01 MODE1/2048
+    INDEX 01 00:00:00
+FILE \"b.bin\" BINARY
+  TRACK 02 AUDIO
+    INDEX 01 00:00:00
+";
+        let mut requested = Vec::new();
+        let mut cue = Cuesheet::from_str_with_resolver(cue, |name| {
+            requested.push(name.to_string());
+            let data = if name == "a.bin" { vec![1u8; 2048] } else { vec![2u8; 2352] };
+            Ok(Box::new(std::io::Cursor::new(data)) as Box<dyn ReadSeek>)
+        }).unwrap();
+        assert_eq!(requested, ["a.bin", "b.bin"]);
+        assert_eq!(cue.track_start(2).unwrap(), MsfIndex::from_lba(151).unwrap());
+
+        let mut buf = [0u8; 2352];
+        cue.set_location_to_track(2).unwrap();
+        cue.copy_current_sector(&mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 2));
+
+        let res = Cuesheet::from_str_with_resolver("FILE \"c.bin\" BINARY", |_| {
+            Err(std::io::Error::from(std::io::ErrorKind::NotFound))
+        });
+        assert!(matches!(res.as_ref().map_err(CueError::kind), Err(CueError::IoError(_))));
+    }
+
+    #[test]
+    fn random_access_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = [[1u8; 2352], [2u8; 2352], [3u8; 2352]].concat();
+        let cue_path = write_files(dir.path(), "\
+FILE \"test.bin\" BINARY
+  TRACK 01 AUDIO
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    INDEX 01 00:00:02
+", &[("test.bin", &bin)]);
+
+        let mut cue = Cuesheet::open(cue_path).unwrap();
+        let mut buf = [0u8; 2352];
+        cue.read_sector_at(152, &mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 3));
+        cue.read_sector_at(10, &mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 0));
+        assert!(cue.read_sector_at(153, &mut buf).is_err());
+        assert_eq!(cue.current_global_msf().unwrap(), MsfIndex::from_lba(150).unwrap());
+        assert_eq!(cue.current_track().unwrap(), 1);
+
+        let mut sectors = vec![0u8; 2 * 2352];
+        cue.read_sectors(151..153, &mut sectors).unwrap();
+        assert_eq!(sectors, bin[2352..]);
+        assert!(cue.read_sectors(151..153, &mut buf).is_err());
+    }
+
+    #[test]
+    fn metadata() {
+        let cue = r#"REM GENRE "Alt Rock"
+REM DATE 1999
+REM DISCID 860B640B
+REM COMMENT "ExactAudioCopy v0.99pb5"
+REM REPLAYGAIN_ALBUM_GAIN -7.89 dB
+REM REPLAYGAIN_ALBUM_PEAK 0.988098
+CATALOG 0724384960650
+PERFORMER "The \"Band\""
+TITLE "C:\Music\Album"
+FILE "a.bin" BINARY
+  TRACK 01 AUDIO
+    TITLE "Intro"
+    PERFORMER Someone Else
+    SONGWRITER "A \\ B"
+    ISRC USABC9900001
+    REM REPLAYGAIN_TRACK_GAIN +1.5 dB
+    REM COMPOSER "Nobody"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    INDEX 01 00:00:01
+"#;
+        let cue = Cuesheet::from_str_with_resolver(cue, |_| {
+            Ok(Box::new(std::io::Cursor::new(vec![0u8; 2 * 2352])) as Box<dyn ReadSeek>)
+        }).unwrap();
+        let metadata = Image::metadata(&cue).unwrap();
+        assert_eq!(metadata.genre.as_deref(), Some("Alt Rock"));
+        assert_eq!(metadata.date.as_deref(), Some("1999"));
+        assert_eq!(metadata.disc_id.as_deref(), Some("860B640B"));
+        assert_eq!(metadata.comment.as_deref(), Some("ExactAudioCopy v0.99pb5"));
+        assert_eq!(metadata.replay_gain.gain, Some(-7.89));
+        assert_eq!(metadata.replay_gain.peak, Some(0.988098));
+        assert_eq!(metadata.catalog.as_deref(), Some("0724384960650"));
+        assert_eq!(metadata.performer.as_deref(), Some("The \"Band\""));
+        assert_eq!(metadata.title.as_deref(), Some("C:\\Music\\Album"));
+
+        assert_eq!(metadata.tracks.len(), 2);
+        let track = metadata.track(1).unwrap();
+        assert_eq!(track.title.as_deref(), Some("Intro"));
+        assert_eq!(track.performer.as_deref(), Some("Someone Else"));
+        assert_eq!(track.songwriter.as_deref(), Some("A \\ B"));
+        assert_eq!(track.isrc.as_deref(), Some("USABC9900001"));
+        assert_eq!(track.replay_gain.gain, Some(1.5));
+        assert_eq!(track.remarks, [("COMPOSER".to_string(), "Nobody".to_string())]);
+        assert_eq!(metadata.track(2).unwrap().title, None);
+
+        let res = Cuesheet::from_str_with_resolver("TITLE \"Unterminated\nFILE \"a.bin\" BINARY", |_| {
+            Ok(Box::new(std::io::Cursor::new(Vec::new())) as Box<dyn ReadSeek>)
+        });
+        assert!(matches!(res.as_ref().map_err(CueError::kind), Err(CueError::InvalidMetadataLine(_))));
+    }
+
+    #[test]
+    fn track_flags() {
+        let cue = "\
+FILE \"a.bin\" BINARY
+  TRACK 01 AUDIO
+    FLAGS PRE DCP 4CH SCMS
+    INDEX 01 00:00:00
+  TRACK 02 MODE1/2352
+    FLAGS DCP
+    INDEX 01 00:00:01
+";
+        let open = |cue: &str| Cuesheet::from_str_with_resolver(cue, |_| {
+            Ok(Box::new(std::io::Cursor::new(vec![0u8; 2 * 2352])) as Box<dyn ReadSeek>)
+        });
+        let mut cue = open(cue).unwrap();
+        let flags = cue.current_track_flags().unwrap();
+        assert_eq!(flags, TrackFlags::PRE_EMPHASIS | TrackFlags::COPY_PERMITTED | TrackFlags::FOUR_CHANNEL | TrackFlags::SCMS);
+        assert_eq!(cue.current_subchannel_q().unwrap()[0], 0xb1);
+        assert_eq!(cue.track_flags(2).unwrap(), TrackFlags::COPY_PERMITTED);
+        assert_eq!(cue.toc().unwrap().track(2).unwrap().control, 0x06);
+        assert!(matches!(cue.track_flags(3), Err(ImageError::OutOfRange)));
+
+        let res = open("FILE \"a.bin\" BINARY\n  TRACK 01 AUDIO\n    FLAGS XYZ\n    INDEX 01 00:00:00");
+        assert!(matches!(res.as_ref().map_err(CueError::kind), Err(CueError::UnknownFlag(_))));
+    }
+
+    #[test]
+    fn cd_text_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let cd_text = crate::cdtext::tests::build_cd_text();
+        let cue_path = write_files(dir.path(), "\
+CDTEXTFILE \"album.cdt\"
+FILE \"test.bin\" BINARY
+  TRACK 01 AUDIO
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    INDEX 01 00:00:01
+", &[("test.bin", &[0u8; 2 * 2352]), ("album.cdt", &cd_text)]);
+
+        let cue = Cuesheet::open(&cue_path).unwrap();
+        let cd_text = cue.cd_text().unwrap();
+        assert_eq!(cd_text.album_title().as_deref(), Some("Album"));
+        assert_eq!(cd_text.track_title(1).as_deref(), Some("Track One"));
+        assert!(cue.sidecars().is_empty());
+
+        // Without CDTEXTFILE the .cdt side-car is used
+        std::fs::rename(dir.path().join("album.cdt"), dir.path().join("test.cdt")).unwrap();
+        let cue_path = write_files(dir.path(), "\
+FILE \"test.bin\" BINARY
+  TRACK 01 AUDIO
+    INDEX 01 00:00:00
+", &[]);
+        let cue = Cuesheet::open(&cue_path).unwrap();
+        assert_eq!(cue.cd_text().unwrap().track_performer(2).as_deref(), Some("Guest"));
+        assert_eq!(cue.sidecars()[0].kind, SidecarKind::CdText);
+    }
+
+    #[test]
+    fn parse_error_position() {
+        let dir = tempfile::tempdir().unwrap();
+        let cue_path = write_files(dir.path(), "\
+FILE \"test.bin\" BINARY
+  TRACK 01 AUDIO
+    INDEX 01 00:00:00
+  TRACK 02 MODE3/2352
+    INDEX 01 00:00:01
+", &[("test.bin", &[0u8; 2 * 2352])]);
+
+        let e = match Cuesheet::open(&cue_path) {
+            Err(CueError::ParseError(e)) => e,
+            _ => panic!("Expected a parse error"),
+        };
+        assert!(matches!(e.error, CueError::UnknownTrackType(_)));
+        assert_eq!(e.path.as_deref(), Some(cue_path.as_path()));
+        assert_eq!((e.line, e.span.clone(), e.column()), (4, 11..21, 12));
+        assert_eq!(e.to_string(), format!("\
+error: Unknown track type MODE3/2352 in cuesheet
+ --> {}:4:12
+  |
+4 |   TRACK 02 MODE3/2352
+  |            ^^^^^^^^^^", cue_path.display()));
+
+        // Errors found at the end of a track point at its TRACK line
+        let res = Cuesheet::from_str_with_resolver("FILE \"a.bin\" BINARY\n\tTRACK 01 AUDIO\n\tFLAGS DCP\n", |_| {
+            Ok(Box::new(std::io::Cursor::new(vec![0u8; 2352])) as Box<dyn ReadSeek>)
+        });
+        let e = match res {
+            Err(CueError::ParseError(e)) => e,
+            _ => panic!("Expected a parse error"),
+        };
+        assert!(matches!(e.error, CueError::TrackWithoutIndex01));
+        assert!(e.path.is_none());
+        assert_eq!((e.line, e.span.clone()), (2, 1..15));
+        assert!(e.to_string().ends_with("2 | \tTRACK 01 AUDIO\n  | \t^^^^^^^^^^^^^^"));
+    }
+
+    #[test]
+    fn lenient() {
+        let dir = tempfile::tempdir().unwrap();
+        let cue_path = write_files(dir.path(), "\u{feff}\
+FILE Disc 1.bin BINARY
+  TRACK 01 MODE1/2352
+    INDEX 01 00:00:00
+  ARRANGER \"Nobody\"
+FILE \"Disc \"2\".bin\" BINARY
+  TRACK 03 AUDIO
+    TITLE \"Three\"
+    INDEX 01 00:00:00
+FILE \" Disc 3.bin \" BINARY
+  TRACK 04 AUDIO
+    INDEX 01 00:00:00
+", &[("Disc 1.bin", &[0u8; 2352]), ("Disc \"2\".bin", &[0u8; 2352]), ("Disc 3.bin", &[0u8; 2352])]);
+
+        let e = Cuesheet::open(&cue_path).err().unwrap();
+        assert!(matches!(e.kind(), CueError::InvalidCommandError(_)));
+
+        let (mut cue, warnings) = Cuesheet::open_lenient(&cue_path, &OpenOptions::new()).unwrap();
+        assert_eq!(cue.num_tracks(), 3);
+        assert_eq!(cue.metadata().unwrap().track(2).unwrap().title.as_deref(), Some("Three"));
+        cue.set_location_to_track(3).unwrap();
+        assert_eq!(cue.current_track_type().unwrap(), TrackType::Audio);
+
+        let kinds = warnings.iter().map(|x| x.kind.clone()).collect::<Vec<_>>();
+        assert_eq!(kinds, [
+            CueWarningKind::ByteOrderMark,
+            CueWarningKind::UnquotedFileName,
+            CueWarningKind::UnknownCommand("ARRANGER".to_string()),
+            CueWarningKind::QuotesInFileName,
+            CueWarningKind::TrackNumberGap { expected: 2, found: 3 },
+            CueWarningKind::WhitespaceAroundFileName,
+        ]);
+        assert_eq!((warnings[1].line, warnings[1].span.clone()), (1, 5..15));
+        assert_eq!(warnings[5].path.as_deref(), Some(cue_path.as_path()));
+        assert_eq!(warnings[4].to_string(), format!("\
+warning: Expected track number 2 instead of 3 in cuesheet
+ --> {}:6:9
+  |
+6 |   TRACK 03 AUDIO
+  |         ^^", cue_path.display()));
+
+        let resolver = |_: &str| Ok(Box::new(std::io::Cursor::new(vec![0u8; 2352])) as Box<dyn ReadSeek>);
+        let e = Cuesheet::from_str_lenient_with_resolver("\
+FILE \"test.bin\" BINARY
+  TRACK 255 AUDIO
+    INDEX 01 00:00:00
+  TRACK 01 AUDIO
+    INDEX 01 00:00:00
+", resolver).err().unwrap();
+        assert!(matches!(e.kind(), CueError::InvalidTrackNumber));
     }
 }
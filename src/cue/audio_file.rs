@@ -0,0 +1,194 @@
+// Parsing of the audio container formats that can be referenced by FILE
+// commands in cuesheets. Only uncompressed CD audio (44.1kHz, 16 bit, stereo)
+// is supported.
+
+use std::convert::TryInto;
+use std::io::{Read, Seek, SeekFrom};
+
+use super::CueError;
+
+
+// 44100 as an 80 bit IEEE 754 extended precision number, as used by AIFF
+const AIFF_RATE_44100: [u8; 10] = [0x40, 0x0e, 0xac, 0x44, 0, 0, 0, 0, 0, 0];
+
+#[derive(Debug, PartialEq)]
+pub(super) struct AudioData {
+    // Offset of the first sample in the file in bytes
+    pub offset: u64,
+    // Length of the sample data in bytes
+    pub len: u64,
+    // Whether the samples are stored in big endian byte order
+    pub big_endian: bool,
+}
+
+fn check_format(channels: u16, sample_rate_ok: bool, bits_per_sample: u16) -> Result<(), CueError> {
+    if channels != 2 || !sample_rate_ok || bits_per_sample != 16 {
+        return Err(CueError::UnsupportedAudioFormat);
+    }
+    Ok(())
+}
+
+// Iterates over the chunks following a 12 byte RIFF or FORM header, calling
+// `f` with the id, size and data offset of each chunk until it returns true.
+fn for_each_chunk<F, R>(file: &mut F, big_endian: bool, mut f: R) -> Result<(), CueError>
+    where F: Read + Seek,
+          R: FnMut(&mut F, [u8; 4], u64, u64) -> Result<bool, CueError>
+{
+    let file_len = file.seek(SeekFrom::End(0))?;
+    let mut pos = 12;
+    while pos + 8 <= file_len {
+        let mut header = [0u8; 8];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut header)?;
+        let id = header[0..4].try_into().unwrap();
+        let size_bytes = header[4..8].try_into().unwrap();
+        let size = if big_endian {
+            u32::from_be_bytes(size_bytes)
+        } else {
+            u32::from_le_bytes(size_bytes)
+        } as u64;
+        if f(file, id, size, pos + 8)? {
+            return Ok(());
+        }
+        // Chunks are padded to an even length
+        pos += 8 + size + (size & 1);
+    }
+    Ok(())
+}
+
+pub(super) fn parse_wave<F>(file: &mut F) -> Result<AudioData, CueError>
+    where F: Read + Seek
+{
+    let mut header = [0u8; 12];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut header).map_err(|_| CueError::InvalidAudioFile)?;
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        return Err(CueError::InvalidAudioFile);
+    }
+
+    let file_len = file.seek(SeekFrom::End(0))?;
+    let mut format_found = false;
+    let mut data = None;
+    for_each_chunk(file, false, |file, id, size, offset| {
+        match &id {
+            b"fmt " => {
+                let mut fmt = [0u8; 16];
+                file.read_exact(&mut fmt).map_err(|_| CueError::InvalidAudioFile)?;
+                let format_tag = u16::from_le_bytes(fmt[0..2].try_into().unwrap());
+                // PCM or WAVE_FORMAT_EXTENSIBLE
+                if format_tag != 1 && format_tag != 0xfffe {
+                    return Err(CueError::UnsupportedAudioFormat);
+                }
+                let channels = u16::from_le_bytes(fmt[2..4].try_into().unwrap());
+                let sample_rate = u32::from_le_bytes(fmt[4..8].try_into().unwrap());
+                let bits_per_sample = u16::from_le_bytes(fmt[14..16].try_into().unwrap());
+                check_format(channels, sample_rate == 44100, bits_per_sample)?;
+                format_found = true;
+            }
+            b"data" => {
+                // Some writers don't fill in the size when streaming
+                let len = if size == 0 || size == 0xffff_ffff {
+                    file_len - offset
+                } else {
+                    size.min(file_len - offset)
+                };
+                data = Some(AudioData { offset, len, big_endian: false });
+            }
+            _ => {}
+        }
+        Ok(format_found && data.is_some())
+    })?;
+
+    match data {
+        Some(data) if format_found => Ok(data),
+        _ => Err(CueError::InvalidAudioFile),
+    }
+}
+
+pub(super) fn parse_aiff<F>(file: &mut F) -> Result<AudioData, CueError>
+    where F: Read + Seek
+{
+    let mut header = [0u8; 12];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut header).map_err(|_| CueError::InvalidAudioFile)?;
+    let is_aifc = match &header[8..12] {
+        b"AIFF" => false,
+        b"AIFC" => true,
+        _ => return Err(CueError::InvalidAudioFile),
+    };
+    if &header[0..4] != b"FORM" {
+        return Err(CueError::InvalidAudioFile);
+    }
+
+    let file_len = file.seek(SeekFrom::End(0))?;
+    let mut big_endian = None;
+    let mut data = None;
+    for_each_chunk(file, true, |file, id, size, offset| {
+        match &id {
+            b"COMM" => {
+                let mut comm = [0u8; 22];
+                let comm = &mut comm[..if is_aifc { 22 } else { 18 }];
+                file.read_exact(comm).map_err(|_| CueError::InvalidAudioFile)?;
+                let channels = u16::from_be_bytes(comm[0..2].try_into().unwrap());
+                let bits_per_sample = u16::from_be_bytes(comm[6..8].try_into().unwrap());
+                check_format(channels, comm[8..18] == AIFF_RATE_44100, bits_per_sample)?;
+                big_endian = Some(match (is_aifc, &comm[18..]) {
+                    (false, _) | (true, b"NONE") => true,
+                    // Little endian samples
+                    (true, b"sowt") => false,
+                    _ => return Err(CueError::UnsupportedAudioFormat),
+                });
+            }
+            b"SSND" => {
+                let mut ssnd = [0u8; 8];
+                file.read_exact(&mut ssnd).map_err(|_| CueError::InvalidAudioFile)?;
+                let data_offset = u32::from_be_bytes(ssnd[0..4].try_into().unwrap()) as u64;
+                let start = offset + 8 + data_offset;
+                if size < 8 + data_offset || start > file_len {
+                    return Err(CueError::InvalidAudioFile);
+                }
+                let len = (size - 8 - data_offset).min(file_len - start);
+                data = Some((start, len));
+            }
+            _ => {}
+        }
+        Ok(big_endian.is_some() && data.is_some())
+    })?;
+
+    match (data, big_endian) {
+        (Some((offset, len)), Some(big_endian)) => Ok(AudioData { offset, len, big_endian }),
+        _ => Err(CueError::InvalidAudioFile),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn wave_and_aiff() {
+        let mut wave = b"RIFF\0\0\0\0WAVE".to_vec();
+        wave.extend(b"LIST\x03\0\0\0abc\0");
+        wave.extend(b"fmt \x10\0\0\0\x01\0\x02\0\x44\xac\0\0\x10\xb1\x02\0\x04\0\x10\0");
+        wave.extend(b"data\x08\0\0\0");
+        wave.extend([1u8; 8]);
+        assert_eq!(parse_wave(&mut Cursor::new(&wave)).unwrap(),
+                   AudioData { offset: 56, len: 8, big_endian: false });
+
+        let mut aiff = b"FORM\0\0\0\0AIFF".to_vec();
+        aiff.extend(b"COMM\0\0\0\x12\0\x02\0\0\0\x02\0\x10");
+        aiff.extend(AIFF_RATE_44100);
+        aiff.extend(b"SSND\0\0\0\x10\0\0\0\0\0\0\0\0");
+        aiff.extend([1u8; 8]);
+        assert_eq!(parse_aiff(&mut Cursor::new(&aiff)).unwrap(),
+                   AudioData { offset: 54, len: 8, big_endian: true });
+
+        // 8 bit mono
+        let mut wave = wave.clone();
+        wave[34] = 1;
+        wave[46] = 8;
+        assert!(matches!(parse_wave(&mut Cursor::new(&wave)), Err(CueError::UnsupportedAudioFormat)));
+    }
+}
@@ -0,0 +1,302 @@
+// Decoding of FLAC files referenced by FILE commands in cuesheets. Only CD
+// audio (44.1kHz, 16 bit, stereo) is supported. claxon can't seek on its own,
+// so we parse the seek table ourselves and restart decoding at the closest
+// seek point whenever a sample before the current position or far after it is
+// requested.
+
+use std::convert::TryInto;
+use std::io::{Read, Seek, SeekFrom};
+
+use claxon::frame::FrameReader;
+use claxon::input::BufferedReader;
+use claxon::{FlacReader, FlacReaderOptions};
+
+use super::CueError;
+use crate::ReadSeek;
+
+
+const BLOCK_TYPE_SEEKTABLE: u8 = 3;
+const SEEK_POINT_PLACEHOLDER: u64 = u64::MAX;
+
+// Bytes per stereo sample
+const SAMPLE_SIZE: u64 = 4;
+
+pub(super) fn is_flac(file: &mut Box<dyn ReadSeek>) -> std::io::Result<bool> {
+    let mut magic = [0u8; 4];
+    file.seek(SeekFrom::Start(0))?;
+    let len = file.read(&mut magic)?;
+    Ok(len == 4 && &magic == b"fLaC")
+}
+
+#[derive(Clone, Copy, Debug)]
+struct SeekPoint {
+    sample: u64,
+    // Offset of the frame relative to the first frame
+    offset: u64,
+}
+
+// Walks the metadata blocks, returning the seek points and the offset of the
+// first frame.
+fn read_metadata(file: &mut Box<dyn ReadSeek>) -> Result<(Vec<SeekPoint>, u64), CueError> {
+    let mut seek_points = Vec::new();
+    let mut pos = 4;
+    loop {
+        let mut header = [0u8; 4];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut header).map_err(|_| CueError::InvalidAudioFile)?;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as u64;
+        if header[0] & 0x7f == BLOCK_TYPE_SEEKTABLE {
+            let mut table = vec![0u8; len as usize];
+            file.read_exact(&mut table).map_err(|_| CueError::InvalidAudioFile)?;
+            for point in table.chunks_exact(18) {
+                let sample = u64::from_be_bytes(point[0..8].try_into().unwrap());
+                if sample != SEEK_POINT_PLACEHOLDER {
+                    let offset = u64::from_be_bytes(point[8..16].try_into().unwrap());
+                    seek_points.push(SeekPoint { sample, offset });
+                }
+            }
+        }
+        pos += 4 + len;
+        if header[0] & 0x80 != 0 {
+            return Ok((seek_points, pos));
+        }
+    }
+}
+
+pub(super) struct FlacFile {
+    // `None` only while repositioning
+    frames: Option<FrameReader<BufferedReader<Box<dyn ReadSeek>>>>,
+    first_frame_offset: u64,
+    seek_points: Vec<SeekPoint>,
+    // Block size of streams using fixed block sizes, for which claxon
+    // computes wrong sample numbers for the (shorter) last block
+    fixed_block_size: Option<u64>,
+    num_samples: u64,
+
+    // Most recently decoded block as 16 bit little endian stereo samples
+    block: Vec<u8>,
+    block_start: u64,
+    decode_buffer: Vec<i32>,
+}
+
+impl FlacFile {
+    pub fn open(mut file: Box<dyn ReadSeek>) -> Result<FlacFile, CueError> {
+        let streaminfo = {
+            file.seek(SeekFrom::Start(0))?;
+            let options = FlacReaderOptions { metadata_only: true, read_vorbis_comment: false };
+            FlacReader::new_ext(&mut file, options)?.streaminfo()
+        };
+        if streaminfo.channels != 2 || streaminfo.sample_rate != 44100 || streaminfo.bits_per_sample != 16 {
+            return Err(CueError::UnsupportedAudioFormat);
+        }
+        let num_samples = streaminfo.samples.ok_or(CueError::InvalidAudioFile)?;
+        let fixed_block_size = if streaminfo.min_block_size == streaminfo.max_block_size {
+            Some(streaminfo.max_block_size as u64)
+        } else {
+            None
+        };
+
+        let (seek_points, first_frame_offset) = read_metadata(&mut file)?;
+        file.seek(SeekFrom::Start(first_frame_offset))?;
+        Ok(FlacFile {
+            frames: Some(FrameReader::new(BufferedReader::new(file))),
+            first_frame_offset,
+            seek_points,
+            fixed_block_size,
+            num_samples,
+            block: Vec::new(),
+            block_start: 0,
+            decode_buffer: Vec::new(),
+        })
+    }
+
+    // Length of the decoded audio in bytes
+    pub fn len(&self) -> u64 {
+        self.num_samples * SAMPLE_SIZE
+    }
+
+    fn block_end(&self) -> u64 {
+        self.block_start + self.block.len() as u64 / SAMPLE_SIZE
+    }
+
+    // Restarts decoding at the frame pointed to by `seek_point`, or the first
+    // frame if it is `None`.
+    fn reposition(&mut self, seek_point: Option<SeekPoint>) -> Result<(), CueError> {
+        let (sample, offset) = seek_point.map_or((0, 0), |x| (x.sample, x.offset));
+        let mut file = self.frames.take().unwrap().into_inner().into_inner();
+        let res = file.seek(SeekFrom::Start(self.first_frame_offset + offset));
+        self.frames = Some(FrameReader::new(BufferedReader::new(file)));
+        res?;
+        self.block.clear();
+        self.block_start = sample;
+        Ok(())
+    }
+
+    fn decode_next_block(&mut self) -> Result<(), CueError> {
+        let buffer = std::mem::take(&mut self.decode_buffer);
+        let block = self.frames.as_mut().unwrap()
+            .read_next_or_eof(buffer)?
+            .ok_or(CueError::InvalidAudioFile)?;
+        self.block_start = match self.fixed_block_size {
+            Some(block_size) if block.duration() > 0 => block.time() / block.duration() as u64 * block_size,
+            _ => block.time(),
+        };
+        self.block.clear();
+        for (left, right) in block.stereo_samples() {
+            self.block.extend((left as i16).to_le_bytes());
+            self.block.extend((right as i16).to_le_bytes());
+        }
+        self.decode_buffer = block.into_buffer();
+        Ok(())
+    }
+
+    // Makes the block containing `sample` the current one
+    fn seek_to_sample(&mut self, sample: u64) -> Result<(), CueError> {
+        if (self.block_start..self.block_end()).contains(&sample) {
+            return Ok(());
+        }
+        let seek_point = self.seek_points.iter().rev().find(|x| x.sample <= sample).copied();
+        let seek_point_start = seek_point.map_or(0, |x| x.sample);
+        // Decoding on from the current block is cheaper than restarting at a
+        // seek point that lies before it.
+        if sample < self.block_start || seek_point_start > self.block_end() {
+            self.reposition(seek_point)?;
+        }
+        loop {
+            self.decode_next_block()?;
+            if sample < self.block_end() {
+                return Ok(());
+            }
+        }
+    }
+
+    // Fills `buf` with the decoded audio starting at byte `offset`
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), CueError> {
+        let mut pos = 0;
+        while pos < buf.len() {
+            let byte = offset + pos as u64;
+            if byte >= self.len() {
+                buf[pos..].fill(0);
+                break;
+            }
+            self.seek_to_sample(byte / SAMPLE_SIZE)?;
+            let block_offset = (byte - self.block_start * SAMPLE_SIZE) as usize;
+            let len = (self.block.len() - block_offset).min(buf.len() - pos);
+            buf[pos..pos + len].copy_from_slice(&self.block[block_offset..block_offset + len]);
+            pos += len;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::cue::Cuesheet;
+    use crate::{track_sha1s, Image, MsfIndex};
+
+    const BLOCK_SIZE: usize = 200;
+
+    fn crc8(data: &[u8]) -> u8 {
+        data.iter().fold(0u8, |mut crc, &x| {
+            crc ^= x;
+            for _ in 0..8 {
+                crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+            }
+            crc
+        })
+    }
+
+    fn crc16(data: &[u8]) -> u16 {
+        data.iter().fold(0u16, |mut crc, &x| {
+            crc ^= (x as u16) << 8;
+            for _ in 0..8 {
+                crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+            }
+            crc
+        })
+    }
+
+    // Encodes 16 bit stereo samples as FLAC using verbatim subframes and a
+    // fixed block size, with a seek table pointing at every other frame.
+    fn encode_flac(samples: &[(i16, i16)]) -> Vec<u8> {
+        let mut frames = Vec::new();
+        let mut seek_table = Vec::new();
+        for (frame_no, block) in samples.chunks(BLOCK_SIZE).enumerate() {
+            if frame_no % 2 == 1 {
+                seek_table.extend(((frame_no * BLOCK_SIZE) as u64).to_be_bytes());
+                seek_table.extend((frames.len() as u64).to_be_bytes());
+                seek_table.extend((block.len() as u16).to_be_bytes());
+            }
+            // Block size stored at the end of the header, 44.1kHz, two
+            // independent channels, 16 bit
+            let mut frame = vec![0xff, 0xf8, 0x69, 0x18, frame_no as u8, block.len() as u8 - 1];
+            frame.push(crc8(&frame));
+            for channel in 0..2 {
+                frame.push(0x02);
+                for &(left, right) in block {
+                    frame.extend(if channel == 0 { left } else { right }.to_be_bytes());
+                }
+            }
+            frame.extend(crc16(&frame).to_be_bytes());
+            frames.extend(frame);
+        }
+
+        let mut flac = b"fLaC".to_vec();
+        flac.extend([0x00, 0, 0, 34]);
+        flac.extend((BLOCK_SIZE as u16).to_be_bytes());
+        flac.extend((BLOCK_SIZE as u16).to_be_bytes());
+        flac.extend([0u8; 6]);
+        let info = (44100u64 << 44) | (1 << 41) | (15 << 36) | samples.len() as u64;
+        flac.extend(info.to_be_bytes());
+        flac.extend([0u8; 16]);
+        flac.push(0x80 | super::BLOCK_TYPE_SEEKTABLE);
+        flac.extend(&(seek_table.len() as u32).to_be_bytes()[1..]);
+        flac.extend(seek_table);
+        flac.extend(frames);
+        flac
+    }
+
+    fn open_cue(dir: &Path, file: &str, mode: &str) -> Cuesheet {
+        let cue_path = dir.join(format!("{}.cue", file));
+        std::fs::write(&cue_path, format!("\
+FILE \"{}\" {}
+  TRACK 01 AUDIO
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    INDEX 01 00:00:01
+", file, mode)).unwrap();
+        Cuesheet::open(cue_path).unwrap()
+    }
+
+    #[test]
+    fn flac_matches_bin() {
+        let dir = tempfile::tempdir().unwrap();
+        let samples: Vec<(i16, i16)> = (0..1276)
+            .map(|i| ((i * 7) as i16, (i * -13 + 1000) as i16))
+            .collect();
+        let bin: Vec<u8> = samples.iter()
+            .flat_map(|(l, r)| [l.to_le_bytes(), r.to_le_bytes()].concat())
+            .chain([0u8; 3 * 2352 - 1276 * 4])
+            .collect();
+        std::fs::write(dir.path().join("test.flac"), encode_flac(&samples)).unwrap();
+        std::fs::write(dir.path().join("test.bin"), &bin).unwrap();
+
+        let mut flac_cue = open_cue(dir.path(), "test.flac", "WAVE");
+        let mut bin_cue = open_cue(dir.path(), "test.bin", "BINARY");
+        assert_eq!(flac_cue.lead_out().unwrap(), MsfIndex::from_lba(153).unwrap());
+        assert_eq!(track_sha1s(&mut flac_cue).unwrap(), track_sha1s(&mut bin_cue).unwrap());
+
+        // Random access, going backwards
+        let mut flac_buf = [0u8; 2352];
+        let mut bin_buf = [0u8; 2352];
+        for lba in [152, 150, 151] {
+            flac_cue.set_location(MsfIndex::from_lba(lba).unwrap()).unwrap();
+            bin_cue.set_location(MsfIndex::from_lba(lba).unwrap()).unwrap();
+            flac_cue.copy_current_sector(&mut flac_buf).unwrap();
+            bin_cue.copy_current_sector(&mut bin_buf).unwrap();
+            assert_eq!(flac_buf[..], bin_buf[..]);
+        }
+    }
+}
@@ -0,0 +1,460 @@
+// Common track layout and navigation for image formats that describe the disc
+// as a table of tracks stored at known offsets in one or more data files
+// (CloneCD and friends). The format specific modules only need to parse their
+// descriptor files into a list of `DiscTrack`s and hand them to `Disc::new`.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+use log::debug;
+
+use vec_map::VecMap;
+
+use crate::cdtext::CdText;
+use crate::options::Sidecars;
+use crate::sbi::SbiRecords;
+use crate::sector::SectorFormat;
+use crate::subchannel;
+use crate::toc::{Toc, TrackFlags, TrackInfo};
+use crate::{Event, Image, ImageError, MsfIndex, Sidecar, TrackType};
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SubchannelLayout {
+    // 96 bytes per sector, each byte holding one bit of each of the P-W channels
+    Interleaved,
+    // 96 bytes per sector, 12 bytes for each of the P-W channels one after another
+    Deinterleaved,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct SubchannelData {
+    // Index into `Disc::files`
+    pub file: usize,
+    // Byte offset of the subchannel data belonging to the sector at
+    // `TrackData::start`
+    pub offset: u64,
+    // Distance in bytes between the subchannel data of consecutive sectors
+    pub stride: u64,
+    pub layout: SubchannelLayout,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct TrackData {
+    // Index into `Disc::files`
+    pub file: usize,
+    // Absolute LBA of the first sector of the track that is actually stored in
+    // the data file. Sectors of the track before this one (like the pregap of
+    // the first track) aren't part of the image and read back as zeros.
+    pub start: u32,
+    // Number of sectors stored in the data file, sectors of the track after
+    // these read back as zeros as well.
+    pub num_sectors: u32,
+    // Byte offset of the sector at `start` in the data file
+    pub offset: u64,
+    // Distance in bytes between consecutive sectors in the data file, at
+    // least `format.data_size()`
+    pub sector_size: u32,
+    pub format: SectorFormat,
+    pub subchannel: Option<SubchannelData>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct DiscTrack {
+    pub track_type: TrackType,
+    // Starts counting from 1
+    pub session: u8,
+    pub flags: TrackFlags,
+    // Absolute LBA of the first sector belonging to this track, i.e. index 00
+    // if the track has a pregap.
+    pub start: u32,
+    pub num_sectors: u32,
+    // Absolute LBAs of the track's indices.
+    // Note: Valid tracks always have an index 1.
+    pub indices: VecMap<u32>,
+    pub data: TrackData,
+}
+
+impl DiscTrack {
+    fn end(&self) -> u32 {
+        self.start + self.num_sectors
+    }
+
+    fn index_one(&self) -> u32 {
+        *self.indices.get(1).unwrap()
+    }
+}
+
+pub(crate) struct Disc {
+    files: Vec<File>,
+    tracks: Vec<DiscTrack>,
+    current_lba: u32,
+    // Starts counting from 0
+    current_track: usize,
+    sidecars: Sidecars,
+}
+
+impl Disc {
+    // `tracks` have to be sorted, non-empty and cover a contiguous range of
+    // sectors starting at LBA 0, apart from the gaps between sessions, which
+    // aren't part of the image.
+    pub fn new(files: Vec<File>, tracks: Vec<DiscTrack>, sidecars: Sidecars) -> Disc {
+        assert!(!tracks.is_empty());
+        let current_lba = tracks[0].index_one();
+        Disc {
+            files,
+            tracks,
+            current_lba,
+            current_track: 0,
+            sidecars,
+        }
+    }
+
+    fn lead_out_lba(&self) -> u32 {
+        self.tracks.last().unwrap().end()
+    }
+
+    // Lead-outs of all sessions, each one directly follows the last track of
+    // its session.
+    fn session_lead_outs(&self) -> Vec<u32> {
+        let mut lead_outs = Vec::new();
+        for (i, track) in self.tracks.iter().enumerate() {
+            match self.tracks.get(i + 1) {
+                Some(next) if next.session == track.session => {}
+                _ => lead_outs.push(track.end()),
+            }
+        }
+        lead_outs
+    }
+
+    fn index_for_lba(&self, track: usize, lba: u32) -> u8 {
+        self.tracks[track].indices.iter()
+            .rev()
+            .find(|(_, &index_lba)| index_lba <= lba)
+            .map_or(0, |(index, _)| index as u8)
+    }
+
+    fn track_for_lba(&self, lba: u32) -> Option<usize> {
+        let contains = |x: &DiscTrack| lba >= x.start && lba < x.end();
+        // Most lookups are for the current track
+        if contains(&self.tracks[self.current_track]) {
+            return Some(self.current_track);
+        }
+        self.tracks.iter().position(contains)
+    }
+
+    pub fn has_subchannel_data(&self) -> bool {
+        self.tracks.iter().all(|x| x.data.subchannel.is_some())
+    }
+
+    // Copies the subchannel data of the current sector as stored in the image,
+    // returning its layout, or `None` if the image doesn't contain it.
+    fn copy_stored_subchannel(&mut self, buf: &mut [u8; 96]) -> Result<Option<SubchannelLayout>, ImageError> {
+        if self.current_lba >= self.lead_out_lba() {
+            return Err(ImageError::OutOfRange);
+        }
+        let data = &self.tracks[self.current_track].data;
+        let sub = match data.subchannel {
+            Some(ref sub) => sub,
+            None => return Ok(None),
+        };
+        if !(data.start..data.start + data.num_sectors).contains(&self.current_lba) {
+            return Ok(None);
+        }
+        let file = &mut self.files[sub.file];
+        file.seek(SeekFrom::Start(sub.offset + (self.current_lba - data.start) as u64 * sub.stride))?;
+        file.read_exact(buf)?;
+        Ok(Some(sub.layout))
+    }
+}
+
+impl Image for Disc {
+    fn num_tracks(&self) -> usize {
+        self.tracks.len()
+    }
+
+    fn sbi_records(&self) -> Option<&SbiRecords> {
+        self.sidecars.sbi_records.as_ref()
+    }
+
+    fn sidecars(&self) -> &[Sidecar] {
+        &self.sidecars.loaded
+    }
+
+    fn failed_sidecars(&self) -> &[(Sidecar, String)] {
+        &self.sidecars.failed
+    }
+
+    fn cd_text(&self) -> Option<&CdText> {
+        self.sidecars.cd_text.as_ref()
+    }
+
+    fn current_track(&self) -> Result<u8, ImageError> {
+        Ok(self.current_track as u8 + 1)
+    }
+
+    fn current_index(&self) -> Result<u8, ImageError> {
+        Ok(self.index_for_lba(self.current_track, self.current_lba))
+    }
+
+    fn current_track_local_msf(&self) -> Result<MsfIndex, ImageError> {
+        let index_one = self.tracks[self.current_track].index_one();
+        if self.current_lba < index_one {
+            // Negative MSFs are (100,0,0) - x
+            let reference = 100 * 60 * 75;
+            let offset = index_one - self.current_lba;
+            Ok(MsfIndex::from_lba(reference - offset)?)
+        } else {
+            Ok(MsfIndex::from_lba(self.current_lba - index_one)?)
+        }
+    }
+
+    fn current_global_msf(&self) -> Result<MsfIndex, ImageError> {
+        Ok(MsfIndex::from_lba(self.current_lba)?)
+    }
+
+    fn current_track_type(&self) -> Result<TrackType, ImageError> {
+        Ok(self.tracks[self.current_track].track_type)
+    }
+
+    fn first_track_type(&self) -> TrackType {
+        self.tracks.first().unwrap().track_type
+    }
+
+    fn track_start(&self, track: u8) -> Result<MsfIndex, ImageError> {
+        let track = self.tracks.get((track as usize).wrapping_sub(1)).ok_or(ImageError::OutOfRange)?;
+        Ok(MsfIndex::from_lba(track.index_one())?)
+    }
+
+    fn track_index_start(&self, track: u8, index: u8) -> Result<MsfIndex, ImageError> {
+        let lba = self.tracks.get((track as usize).wrapping_sub(1))
+            .and_then(|x| x.indices.get(index as usize))
+            .ok_or(ImageError::OutOfRange)?;
+        Ok(MsfIndex::from_lba(*lba)?)
+    }
+
+    fn lead_out(&self) -> Result<MsfIndex, ImageError> {
+        Ok(MsfIndex::from_lba(self.lead_out_lba())?)
+    }
+
+    fn toc(&self) -> Result<Toc, ImageError> {
+        let mut tracks = Vec::new();
+        for (i, track) in self.tracks.iter().enumerate() {
+            let index_one = track.index_one();
+            let indices = track.indices.iter()
+                .map(|(index, &lba)| Ok((index as u8, MsfIndex::from_lba(lba)?)))
+                .collect::<Result<Vec<_>, ImageError>>()?;
+            tracks.push(TrackInfo {
+                number: i as u8 + 1,
+                session: track.session,
+                track_type: track.track_type,
+                flags: track.flags,
+                control: track.flags.control(track.track_type),
+                start: MsfIndex::from_lba(index_one)?,
+                length: track.end() - index_one,
+                indices,
+                pregap: index_one - track.start,
+                postgap: 0,
+            });
+        }
+        Ok(Toc::from_tracks(tracks, &self.session_lead_outs())?)
+    }
+
+    fn track_flags(&self, track: u8) -> Result<TrackFlags, ImageError> {
+        let track = self.tracks.get((track as usize).wrapping_sub(1)).ok_or(ImageError::OutOfRange)?;
+        Ok(track.flags)
+    }
+
+    fn set_location(&mut self, target: MsfIndex) -> Result<(), ImageError> {
+        let lba = target.to_lba();
+        if let Some(track) = self.track_for_lba(lba) {
+            self.current_lba = lba;
+            self.current_track = track;
+            debug!("set_location {:?}, track {}", target, track + 1);
+            Ok(())
+        } else {
+            Err(ImageError::OutOfRange)
+        }
+    }
+
+    fn set_location_to_track(&mut self, track: u8) -> Result<(), ImageError> {
+        let track_start = self.track_start(track)?;
+        self.set_location(track_start)
+    }
+
+    fn advance_position(&mut self) -> Result<Option<Event>, ImageError> {
+        let old_index = self.index_for_lba(self.current_track, self.current_lba);
+        self.current_lba += 1;
+        if self.current_lba < self.tracks[self.current_track].end() {
+            if self.index_for_lba(self.current_track, self.current_lba) != old_index {
+                Ok(Some(Event::IndexChange))
+            } else {
+                Ok(None)
+            }
+        } else if self.current_track + 1 < self.tracks.len() {
+            self.current_track += 1;
+            // Skip the gap in front of the first track of a new session
+            self.current_lba = self.current_lba.max(self.tracks[self.current_track].start);
+            Ok(Some(Event::TrackChange))
+        } else {
+            Ok(Some(Event::EndOfDisc))
+        }
+    }
+
+    fn copy_current_sector(&mut self, buf: &mut [u8]) -> Result<(), ImageError> {
+        self.read_sector_at(self.current_lba, buf)
+    }
+
+    fn copy_current_subchannel_unpatched(&mut self, buf: &mut [u8; 96]) -> Result<(), ImageError> {
+        let mut stored = [0u8; 96];
+        match self.copy_stored_subchannel(&mut stored)? {
+            Some(SubchannelLayout::Interleaved) => *buf = stored,
+            Some(SubchannelLayout::Deinterleaved) => subchannel::interleave(&stored, buf),
+            None => subchannel::synthesize(&*self, buf)?,
+        }
+        Ok(())
+    }
+
+    fn copy_current_subchannel_deinterleaved(&mut self, buf: &mut [u8; 96]) -> Result<(), ImageError> {
+        let mut stored = [0u8; 96];
+        match self.copy_stored_subchannel(&mut stored)? {
+            Some(SubchannelLayout::Interleaved) => subchannel::deinterleave(&stored, buf),
+            Some(SubchannelLayout::Deinterleaved) => *buf = stored,
+            None => {
+                subchannel::synthesize(&*self, &mut stored)?;
+                subchannel::deinterleave(&stored, buf);
+            }
+        }
+        if let Some(record) = self.sbi_record(self.current_lba) {
+            record.apply(&mut buf[12..24]);
+        }
+        Ok(())
+    }
+
+    fn read_sector_at(&mut self, lba: u32, buf: &mut [u8]) -> Result<(), ImageError> {
+        let track = self.track_for_lba(lba).ok_or(ImageError::OutOfRange)?;
+        let data = &self.tracks[track].data;
+        if !(data.start..data.start + data.num_sectors).contains(&lba) {
+            buf.fill(0);
+            return Ok(());
+        }
+        let offset = data.offset + (lba - data.start) as u64 * data.sector_size as u64;
+        let file = &mut self.files[data.file];
+        file.seek(SeekFrom::Start(offset))?;
+        if data.format == SectorFormat::Raw {
+            file.read_exact(buf)?;
+        } else {
+            let mut sector_data = [0u8; 2352];
+            let sector_data = &mut sector_data[..data.format.data_size()];
+            file.read_exact(sector_data)?;
+            data.format.expand(lba, sector_data, buf);
+        }
+        Ok(())
+    }
+}
+
+// Implements `Image` for a wrapper type by forwarding everything to its
+// `disc` field.
+macro_rules! forward_image_to_disc {
+    ($ty:ty) => {
+        impl crate::Image for $ty {
+            fn num_tracks(&self) -> usize {
+                self.disc.num_tracks()
+            }
+
+            fn sbi_records(&self) -> Option<&crate::sbi::SbiRecords> {
+                self.disc.sbi_records()
+            }
+
+            fn sidecars(&self) -> &[crate::Sidecar] {
+                self.disc.sidecars()
+            }
+
+            fn failed_sidecars(&self) -> &[(crate::Sidecar, String)] {
+                self.disc.failed_sidecars()
+            }
+
+            fn cd_text(&self) -> Option<&crate::cdtext::CdText> {
+                self.disc.cd_text()
+            }
+
+            fn current_track(&self) -> Result<u8, crate::ImageError> {
+                self.disc.current_track()
+            }
+
+            fn current_index(&self) -> Result<u8, crate::ImageError> {
+                self.disc.current_index()
+            }
+
+            fn current_track_local_msf(&self) -> Result<crate::MsfIndex, crate::ImageError> {
+                self.disc.current_track_local_msf()
+            }
+
+            fn current_global_msf(&self) -> Result<crate::MsfIndex, crate::ImageError> {
+                self.disc.current_global_msf()
+            }
+
+            fn current_track_type(&self) -> Result<crate::TrackType, crate::ImageError> {
+                self.disc.current_track_type()
+            }
+
+            fn first_track_type(&self) -> crate::TrackType {
+                self.disc.first_track_type()
+            }
+
+            fn track_start(&self, track: u8) -> Result<crate::MsfIndex, crate::ImageError> {
+                self.disc.track_start(track)
+            }
+
+            fn track_index_start(&self, track: u8, index: u8) -> Result<crate::MsfIndex, crate::ImageError> {
+                self.disc.track_index_start(track, index)
+            }
+
+            fn lead_out(&self) -> Result<crate::MsfIndex, crate::ImageError> {
+                self.disc.lead_out()
+            }
+
+            fn toc(&self) -> Result<crate::Toc, crate::ImageError> {
+                self.disc.toc()
+            }
+
+            fn track_flags(&self, track: u8) -> Result<crate::TrackFlags, crate::ImageError> {
+                self.disc.track_flags(track)
+            }
+
+            fn set_location(&mut self, target: crate::MsfIndex) -> Result<(), crate::ImageError> {
+                self.disc.set_location(target)
+            }
+
+            fn set_location_to_track(&mut self, track: u8) -> Result<(), crate::ImageError> {
+                self.disc.set_location_to_track(track)
+            }
+
+            fn advance_position(&mut self) -> Result<Option<crate::Event>, crate::ImageError> {
+                self.disc.advance_position()
+            }
+
+            fn copy_current_sector(&mut self, buf: &mut [u8]) -> Result<(), crate::ImageError> {
+                self.disc.copy_current_sector(buf)
+            }
+
+            fn copy_current_subchannel(&mut self, buf: &mut [u8; 96]) -> Result<(), crate::ImageError> {
+                self.disc.copy_current_subchannel(buf)
+            }
+
+            fn copy_current_subchannel_unpatched(&mut self, buf: &mut [u8; 96]) -> Result<(), crate::ImageError> {
+                self.disc.copy_current_subchannel_unpatched(buf)
+            }
+
+            fn copy_current_subchannel_deinterleaved(&mut self, buf: &mut [u8; 96]) -> Result<(), crate::ImageError> {
+                self.disc.copy_current_subchannel_deinterleaved(buf)
+            }
+
+            fn read_sector_at(&mut self, lba: u32, buf: &mut [u8]) -> Result<(), crate::ImageError> {
+                self.disc.read_sector_at(lba, buf)
+            }
+        }
+    };
+}
+
+pub(crate) use forward_image_to_disc;
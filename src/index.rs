@@ -1,4 +1,3 @@
-use std;
 use std::cmp::Ordering;
 use std::fmt;
 
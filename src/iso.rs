@@ -0,0 +1,121 @@
+use std::fs::File;
+use std::path::Path;
+
+use log::warn;
+
+use thiserror::Error;
+
+use vec_map::VecMap;
+
+use crate::disc::{Disc, DiscTrack, TrackData};
+use crate::sector::SectorFormat;
+use crate::{OpenOptions, TrackFlags, TrackType};
+
+
+#[derive(Debug, Error)]
+pub enum IsoError {
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    SidecarError(#[from] crate::SidecarError),
+    #[error("ISO file is empty")]
+    Empty,
+}
+
+/// A plain image of 2048 byte sectors (`.iso`), presented as a disc with a
+/// single Mode 1 track. Sync pattern, header and EDC/ECC of the raw sectors
+/// are synthesized when reading.
+pub struct IsoImage {
+    disc: Disc,
+}
+
+impl IsoImage {
+    pub fn open<P>(path: P) -> Result<IsoImage, IsoError>
+        where P: AsRef<Path>
+    {
+        Self::_open(path.as_ref(), &OpenOptions::new())
+    }
+
+    /// Like `open`, but loads the side-car files according to `options`.
+    pub fn open_with_options<P>(path: P, options: &OpenOptions) -> Result<IsoImage, IsoError>
+        where P: AsRef<Path>
+    {
+        Self::_open(path.as_ref(), options)
+    }
+
+    fn _open(path: &Path, options: &OpenOptions) -> Result<IsoImage, IsoError> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        if len % 2048 != 0 {
+            warn!("Size of ISO file is not a multiple of 2048 bytes.");
+        }
+        let num_sectors = (len / 2048) as u32;
+        if num_sectors == 0 {
+            return Err(IsoError::Empty);
+        }
+
+        let mut indices = VecMap::new();
+        indices.insert(0, 0);
+        indices.insert(1, 150);
+        let track = DiscTrack {
+            track_type: TrackType::Mode1,
+            session: 1,
+            flags: TrackFlags::empty(),
+            start: 0,
+            num_sectors: 150 + num_sectors,
+            indices,
+            data: TrackData {
+                file: 0,
+                start: 150,
+                num_sectors,
+                offset: 0,
+                sector_size: 2048,
+                format: SectorFormat::Mode1Cooked,
+                subchannel: None,
+            },
+        };
+
+        let sidecars = options.load_sidecars(path)?;
+
+        Ok(IsoImage {
+            disc: Disc::new(vec![file], vec![track], sidecars),
+        })
+    }
+}
+
+crate::disc::forward_image_to_disc!(IsoImage);
+
+#[cfg(test)]
+mod tests {
+    use sha1::{Digest, Sha1};
+
+    use crate::{open_file, MsfIndex, OpenOptions, SidecarKind};
+
+    #[test]
+    fn iso_sectors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.iso");
+        let data: Vec<u8> = (0..3).flat_map(|x| [x as u8; 2048]).collect();
+        std::fs::write(&path, data).unwrap();
+
+        let mut image = open_file(&path).unwrap();
+        assert_eq!(image.num_tracks(), 1);
+        assert_eq!(image.lead_out().unwrap(), MsfIndex::from_lba(153).unwrap());
+
+        let mut buf = [0u8; 2352];
+        image.set_location(MsfIndex::from_lba(152).unwrap()).unwrap();
+        image.copy_current_sector(&mut buf).unwrap();
+        assert_eq!(&buf[..16], &[0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                                 0xff, 0xff, 0xff, 0x00, 0x00, 0x02, 0x02, 0x01]);
+        assert!(buf[16..2064].iter().all(|&x| x == 2));
+        // Whole sector including EDC and ECC as generated by MAME's cdrom.c
+        let sha1: [u8; 20] = Sha1::digest(buf).into();
+        assert_eq!(sha1, [0x3f, 0x3b, 0x12, 0x89, 0x73, 0x9a, 0xfa, 0xef, 0x92, 0x55,
+                          0xe8, 0x2f, 0x21, 0x31, 0x83, 0xe3, 0x80, 0x21, 0x11, 0x72]);
+
+        std::fs::write(path.with_extension("sbi"), b"SBI\0\x00\x02\x00\x03\x00\x02\x01").unwrap();
+        let image = OpenOptions::new().strict(true).open(&path).unwrap();
+        assert_eq!(image.sidecars()[0].kind, SidecarKind::Sbi);
+        assert!(OpenOptions::new().detect_sidecars(false).open(&path).unwrap().sidecars().is_empty());
+    }
+}
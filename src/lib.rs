@@ -1,17 +1,35 @@
+pub mod cdi;
+pub mod cdtext;
 pub mod cue;
 #[cfg(feature = "chd")]
 pub mod chd;
+pub mod clonecd;
+mod disc;
 mod index;
-mod sbi;
+pub mod iso;
+pub mod mds;
+mod metadata;
+pub mod nrg;
+mod options;
+pub mod sbi;
+mod sector;
+mod subchannel;
+mod toc;
 
 pub use self::index::{MsfIndex, MsfIndexError};
+pub use self::metadata::{Metadata, ReplayGain, TrackMetadata};
+pub use self::options::{OpenOptions, Sidecar, SidecarError, SidecarKind};
+pub use self::toc::{Session, Toc, TrackFlags, TrackInfo};
 
+use std::io::{Read, Seek};
+use std::ops::Range;
 use std::path::Path;
-#[cfg(feature = "chd")]
-use std::{fs::File, io::Read};
 
 use log::{debug, error, info, warn};
 
+#[cfg(feature = "serde-support")]
+use serde_derive::{Deserialize, Serialize};
+
 use thiserror::Error;
 
 
@@ -25,16 +43,58 @@ pub enum ImageError {
     #[error(transparent)]
     ChdError(#[from] chd::ChdImageError),
     #[error(transparent)]
+    CdiError(#[from] cdi::CdiError),
+    #[error(transparent)]
+    CloneCdError(#[from] clonecd::CloneCdError),
+    #[error(transparent)]
+    IsoError(#[from] iso::IsoError),
+    #[error(transparent)]
+    MdsError(#[from] mds::MdsError),
+    #[error(transparent)]
+    NrgError(#[from] nrg::NrgError),
+    #[error(transparent)]
     MsfIndexError(#[from] MsfIndexError),
     #[error(transparent)]
     IoError(#[from] std::io::Error),
     #[error("Index out of range")]
     OutOfRange,
+    #[error("Wrong buffer size, needs to be 2352 bytes per sector")]
+    WrongBufferSize,
 }
 
+/// A seekable source of image data, like a file or an in-memory buffer.
+pub trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
 pub trait Image {
     fn num_tracks(&self) -> usize;
-    fn current_subchannel_q_valid(&self) -> bool;
+    /// Returns `false` for sectors listed in the SBI or LSD file loaded
+    /// alongside the image, i.e. the ones whose Q subchannel was modified
+    /// (LibCrypt).
+    fn current_subchannel_q_valid(&self) -> bool {
+        self.current_global_msf().map_or(true, |msf| self.sbi_record(msf.to_lba()).is_none())
+    }
+    /// Returns the records of the SBI or LSD file loaded alongside the image.
+    fn sbi_records(&self) -> Option<&sbi::SbiRecords>;
+    /// Returns the Q subchannel data stored in the loaded SBI or LSD file for
+    /// the sector at `lba`, if any.
+    fn sbi_record(&self, lba: u32) -> Option<&sbi::SbiRecord> {
+        self.sbi_records().and_then(|x| x.get(&lba))
+    }
+    /// Returns the side-car files that were loaded alongside the image.
+    fn sidecars(&self) -> &[Sidecar];
+    /// Returns the side-car files that were found but failed to load, along
+    /// with the reason. Only non-strict opening ignores such failures.
+    fn failed_sidecars(&self) -> &[(Sidecar, String)];
+    /// Returns textual information about the disc and its tracks, if the
+    /// image format stores any (like cuesheets do).
+    fn metadata(&self) -> Option<&Metadata> {
+        None
+    }
+    /// Returns the CD-TEXT data of the disc, e.g. from a `.cdt` file loaded
+    /// alongside the image.
+    fn cd_text(&self) -> Option<&cdtext::CdText>;
     fn current_track(&self) -> Result<u8, ImageError>;
     fn current_index(&self) -> Result<u8, ImageError>;
     fn current_track_local_msf(&self) -> Result<MsfIndex, ImageError>;
@@ -42,35 +102,99 @@ pub trait Image {
     fn current_track_type(&self) -> Result<TrackType, ImageError>;
     fn first_track_type(&self) -> TrackType;
     fn track_start(&self, track: u8) -> Result<MsfIndex, ImageError>;
+    /// Returns the position of the given index of `track`, or
+    /// `ImageError::OutOfRange` if the track has no such index.
+    fn track_index_start(&self, track: u8, index: u8) -> Result<MsfIndex, ImageError>;
+    /// Returns the position of the lead-out, i.e. the first sector after the
+    /// end of the last track. As positions start at 00:00:00, this is also
+    /// the length of the disc including the two seconds before track 1.
+    fn lead_out(&self) -> Result<MsfIndex, ImageError>;
+    /// Returns the layout of the whole disc.
+    fn toc(&self) -> Result<Toc, ImageError>;
+    /// Returns the flags of the given track, as given by the cuesheet's FLAGS
+    /// command or the control field stored in the image.
+    fn track_flags(&self, track: u8) -> Result<TrackFlags, ImageError>;
+    fn current_track_flags(&self) -> Result<TrackFlags, ImageError> {
+        self.track_flags(self.current_track()?)
+    }
 
     fn set_location(&mut self, target: MsfIndex) -> Result<(), ImageError>;
     fn set_location_to_track(&mut self, track: u8) -> Result<(), ImageError>;
+    fn set_location_to_track_index(&mut self, track: u8, index: u8) -> Result<(), ImageError> {
+        let target = self.track_index_start(track, index)?;
+        self.set_location(target)
+    }
     fn advance_position(&mut self) -> Result<Option<Event>, ImageError>;
     #[allow(unused)]
     fn advise_prefetch(&mut self, location: MsfIndex) {}
 
     /// `buf` is expected to be 2352 bytes long
     fn copy_current_sector(&mut self, buf: &mut[u8]) -> Result<(), ImageError>;
-}
 
-pub fn open_file<P>(path: P) -> Result<Box<dyn Image>, ImageError>
-    where P: AsRef<Path>
-{
-    #[cfg(feature = "chd")] {
-        let mut magic = [0u8; 8];
-        File::open(path.as_ref())?.read_exact(&mut magic)?;
-        if &magic == b"MComprHD" {
-            return Ok(Box::new(chd::ChdImage::open(path.as_ref())?));
+    /// Copies the P-W subchannel data of the current sector in the raw
+    /// interleaved format, i.e. each byte holds one bit of every channel with
+    /// P in the most significant bit. For images that don't store
+    /// subchannel data, P (set in pregaps) and Q (the position) are
+    /// synthesized and R-W are empty.
+    fn copy_current_subchannel(&mut self, buf: &mut [u8; 96]) -> Result<(), ImageError> {
+        self.copy_current_subchannel_unpatched(buf)?;
+        let lba = self.current_global_msf()?.to_lba();
+        if let Some(record) = self.sbi_record(lba) {
+            subchannel::apply_sbi_record(buf, record);
         }
+        Ok(())
+    }
+
+    /// Like `copy_current_subchannel`, but ignores the SBI or LSD file loaded
+    /// alongside the image, i.e. returns the subchannel data as stored in the
+    /// image (or synthesized).
+    fn copy_current_subchannel_unpatched(&mut self, buf: &mut [u8; 96]) -> Result<(), ImageError>;
+
+    /// Like `copy_current_subchannel`, but deinterleaved into 12 bytes for
+    /// each of the channels P to W.
+    fn copy_current_subchannel_deinterleaved(&mut self, buf: &mut [u8; 96]) -> Result<(), ImageError> {
+        let mut interleaved = [0u8; 96];
+        self.copy_current_subchannel(&mut interleaved)?;
+        subchannel::deinterleave(&interleaved, buf);
+        Ok(())
+    }
+
+    /// Returns the 12 byte Q subchannel frame of the current sector:
+    /// control/ADR, track and index, relative and absolute MSF (all BCD
+    /// encoded) and the CRC-16. For sectors listed in a loaded SBI or LSD
+    /// file the replacement data from it is returned. SBI files don't store
+    /// the CRC, so one matching their data is computed.
+    fn current_subchannel_q(&mut self) -> Result<[u8; 12], ImageError> {
+        let mut channels = [0u8; 96];
+        self.copy_current_subchannel_deinterleaved(&mut channels)?;
+        let mut q = [0u8; 12];
+        q.copy_from_slice(&channels[12..24]);
+        Ok(q)
     }
 
-    if let Some(ext) = path.as_ref().extension() {
-        if ext.to_string_lossy().to_lowercase() == "cue" {
-            return Ok(Box::new(cue::Cuesheet::open(path)?));
+    /// Reads the sector at `lba` (the absolute position, i.e. 150 for
+    /// 00:02:00) without changing the current position. `buf` is expected to
+    /// be 2352 bytes long.
+    fn read_sector_at(&mut self, lba: u32, buf: &mut [u8]) -> Result<(), ImageError>;
+
+    /// Reads all sectors in `range` into `buf`, which has to be 2352 bytes
+    /// per sector long, without changing the current position.
+    fn read_sectors(&mut self, range: Range<u32>, buf: &mut [u8]) -> Result<(), ImageError> {
+        if buf.len() != range.len() * 2352 {
+            return Err(ImageError::WrongBufferSize);
         }
+        for (lba, sector_buf) in range.zip(buf.chunks_exact_mut(2352)) {
+            self.read_sector_at(lba, sector_buf)?;
+        }
+        Ok(())
     }
+}
 
-    Err(ImageError::UnsupportedFormat)
+/// Opens the image at `path` with the default `OpenOptions`.
+pub fn open_file<P>(path: P) -> Result<Box<dyn Image>, ImageError>
+    where P: AsRef<Path>
+{
+    OpenOptions::new().open(path)
 }
 
 pub fn track_sha1s<I>(image: &mut I) -> Result<Vec<[u8; 20]>, ImageError>
@@ -89,7 +213,7 @@ pub fn track_sha1s<I>(image: &mut I) -> Result<Vec<[u8; 20]>, ImageError>
         let mut event = None;
         while event != Some(Event::TrackChange) && event != Some(Event::EndOfDisc) {
             image.copy_current_sector(&mut sector_buf)?;
-            hasher.update(&sector_buf);
+            hasher.update(sector_buf);
             event = image.advance_position()?;
         }
 
@@ -106,6 +230,7 @@ pub fn track_sha1s<I>(image: &mut I) -> Result<Vec<[u8; 20]>, ImageError>
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde-support", derive(Serialize, Deserialize))]
 pub enum TrackType {
     // 2352 Bytes User Data, 2352 Bytes Raw Data
     Audio,
@@ -115,8 +240,10 @@ pub enum TrackType {
     Mode2
 }
 
-#[derive(PartialEq)]
+#[derive(Debug, PartialEq)]
 pub enum Event {
     TrackChange,
+    // Emitted when moving to another index within the same track
+    IndexChange,
     EndOfDisc
 }
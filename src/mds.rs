@@ -0,0 +1,483 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+
+use thiserror::Error;
+
+use vec_map::VecMap;
+
+use crate::disc::{Disc, DiscTrack, SubchannelData, SubchannelLayout, TrackData};
+use crate::sector::SectorFormat;
+use crate::{MsfIndex, OpenOptions, TrackFlags, TrackType};
+
+
+pub(crate) const MDS_MAGIC: &[u8; 16] = b"MEDIA DESCRIPTOR";
+
+const HEADER_LEN: usize = 88;
+const SESSION_BLOCK_LEN: usize = 24;
+const TRACK_BLOCK_LEN: usize = 80;
+
+#[derive(Debug, Error)]
+pub enum MdsError {
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    SidecarError(#[from] crate::SidecarError),
+    #[error("Input file does not seem like an MDS file (Magic doesn't match)")]
+    NotAnMdsFile,
+    #[error("Unsupported MDS version {0}.{1}")]
+    UnsupportedVersion(u8, u8),
+    #[error("Unsupported medium type {0:#x} (not a CD)")]
+    UnsupportedMediumType(u16),
+    #[error("MDS file is truncated or contains an invalid offset")]
+    Truncated,
+    #[error("Unsupported track mode {0:#x} in MDS file")]
+    UnsupportedTrackMode(u8),
+    #[error("Unsupported sector size {0} in MDS file")]
+    UnsupportedSectorSize(u16),
+    #[error("Invalid track number in MDS file")]
+    InvalidTrackNumber,
+    #[error("Invalid track layout in MDS file")]
+    InvalidTrackLayout,
+    #[error("No tracks in MDS file")]
+    NoTracks,
+}
+
+fn bytes(data: &[u8], offset: usize, len: usize) -> Result<&[u8], MdsError> {
+    data.get(offset..offset + len).ok_or(MdsError::Truncated)
+}
+
+fn u8_at(data: &[u8], offset: usize) -> Result<u8, MdsError> {
+    data.get(offset).copied().ok_or(MdsError::Truncated)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, MdsError> {
+    Ok(u16::from_le_bytes(bytes(data, offset, 2)?.try_into().unwrap()))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, MdsError> {
+    Ok(u32::from_le_bytes(bytes(data, offset, 4)?.try_into().unwrap()))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Result<u64, MdsError> {
+    Ok(u64::from_le_bytes(bytes(data, offset, 8)?.try_into().unwrap()))
+}
+
+// Reads the null-terminated file name referenced by a track's footer block
+fn file_name(data: &[u8], footer_offset: usize) -> Result<String, MdsError> {
+    let name_offset = u32_at(data, footer_offset)? as usize;
+    let wide = u32_at(data, footer_offset + 4)? != 0;
+    let rest = data.get(name_offset..).ok_or(MdsError::Truncated)?;
+    if wide {
+        let chars: Vec<u16> = rest.chunks_exact(2)
+            .map(|x| u16::from_le_bytes([x[0], x[1]]))
+            .take_while(|&x| x != 0)
+            .collect();
+        Ok(String::from_utf16_lossy(&chars))
+    } else {
+        let len = rest.iter().position(|&x| x == 0).unwrap_or(rest.len());
+        Ok(String::from_utf8_lossy(&rest[..len]).into_owned())
+    }
+}
+
+struct MdsTrack {
+    track_type: TrackType,
+    flags: TrackFlags,
+    format: SectorFormat,
+    has_subchannel: bool,
+    sector_size: u16,
+    // Absolute LBA of index 01
+    index_one: u32,
+    pregap: u32,
+    length: Option<u32>,
+    offset: u64,
+    file_name: String,
+}
+
+fn parse_track_block(data: &[u8], block: usize) -> Result<MdsTrack, MdsError> {
+    let mode = u8_at(data, block)?;
+    let subchannel = u8_at(data, block + 1)?;
+    // ADR in the upper, control in the lower nibble
+    let adr_control = u8_at(data, block + 2)?;
+    let extra_offset = u32_at(data, block + 0x0c)? as usize;
+    let sector_size = u16_at(data, block + 0x10)?;
+    let start_sector = u32_at(data, block + 0x24)?;
+    let offset = u64_at(data, block + 0x28)?;
+    let footer_offset = u32_at(data, block + 0x34)? as usize;
+
+    let track_type = match mode & 0x0f {
+        0x09 => TrackType::Audio,
+        0x0a => TrackType::Mode1,
+        0x0b..=0x0d => TrackType::Mode2,
+        _ => return Err(MdsError::UnsupportedTrackMode(mode)),
+    };
+    let format = match (sector_size, track_type) {
+        (2352, _) | (2448, _) => SectorFormat::Raw,
+        (2048, TrackType::Mode1) => SectorFormat::Mode1Cooked,
+        (2048, TrackType::Mode2) => SectorFormat::Mode2Form1Cooked,
+        (2336, TrackType::Mode2) => SectorFormat::Mode2Cooked,
+        _ => return Err(MdsError::UnsupportedSectorSize(sector_size)),
+    };
+    let has_subchannel = sector_size == 2448;
+    if has_subchannel && subchannel != 0x08 {
+        warn!("Unexpected subchannel mode {:#x} for sector size 2448", subchannel);
+    }
+
+    // The extra block only exists for tracks with a pregap or known length
+    let (pregap, length) = if extra_offset != 0 {
+        (u32_at(data, extra_offset)?, Some(u32_at(data, extra_offset + 4)?))
+    } else {
+        (0, None)
+    };
+
+    let file_name = if footer_offset != 0 {
+        file_name(data, footer_offset)?
+    } else {
+        "*.mdf".to_string()
+    };
+
+    Ok(MdsTrack {
+        track_type,
+        flags: TrackFlags::from_control(adr_control & 0x0f),
+        format,
+        has_subchannel,
+        sector_size,
+        index_one: start_sector + 150,
+        pregap,
+        length,
+        offset,
+        file_name,
+    })
+}
+
+/// An image in Alcohol 120% format, consisting of the binary `.mds`
+/// descriptor and the `.mdf` file(s) containing the sector data.
+pub struct MdsImage {
+    disc: Disc,
+}
+
+impl MdsImage {
+    /// Opens the image described by the `.mds` file at `path`.
+    pub fn open<P>(path: P) -> Result<MdsImage, MdsError>
+        where P: AsRef<Path>
+    {
+        Self::_open(path.as_ref(), &OpenOptions::new())
+    }
+
+    /// Like `open`, but loads the side-car files according to `options`.
+    pub fn open_with_options<P>(path: P, options: &OpenOptions) -> Result<MdsImage, MdsError>
+        where P: AsRef<Path>
+    {
+        Self::_open(path.as_ref(), options)
+    }
+
+    fn _open(path: &Path, options: &OpenOptions) -> Result<MdsImage, MdsError> {
+        let data = std::fs::read(path)?;
+        if data.len() < HEADER_LEN || &data[..16] != MDS_MAGIC {
+            return Err(MdsError::NotAnMdsFile);
+        }
+        let (version_major, version_minor) = (data[16], data[17]);
+        if version_major != 1 {
+            return Err(MdsError::UnsupportedVersion(version_major, version_minor));
+        }
+        let medium_type = u16_at(&data, 18)?;
+        if medium_type > 0x02 {
+            return Err(MdsError::UnsupportedMediumType(medium_type));
+        }
+        let num_sessions = u16_at(&data, 20)? as usize;
+        let sessions_offset = u32_at(&data, 80)? as usize;
+
+        // Point -> (session, track)
+        let mut mds_tracks: BTreeMap<u8, (u8, MdsTrack)> = BTreeMap::new();
+        // Session -> absolute LBA of its lead-out
+        let mut lead_outs: BTreeMap<u8, u32> = BTreeMap::new();
+        for session_no in 0..num_sessions {
+            let session_block = sessions_offset + session_no * SESSION_BLOCK_LEN;
+            let num_blocks = u8_at(&data, session_block + 10)? as usize;
+            let tracks_offset = u32_at(&data, session_block + 20)? as usize;
+
+            for block_no in 0..num_blocks {
+                let block = tracks_offset + block_no * TRACK_BLOCK_LEN;
+                let point = u8_at(&data, block + 4)?;
+                if (1..=99).contains(&point) {
+                    let track = parse_track_block(&data, block)?;
+                    debug!("MDS track {}: index 01 at {}, pregap {}, length {:?}, sector size {}",
+                           point, track.index_one, track.pregap, track.length, track.sector_size);
+                    mds_tracks.insert(point, (session_no as u8 + 1, track));
+                } else if point == 0xa2 {
+                    let (m, s, f) = (u8_at(&data, block + 9)?, u8_at(&data, block + 10)?, u8_at(&data, block + 11)?);
+                    if let Ok(msf) = MsfIndex::new(m, s, f) {
+                        lead_outs.insert(session_no as u8 + 1, msf.to_lba());
+                    }
+                }
+            }
+        }
+        if mds_tracks.is_empty() {
+            return Err(MdsError::NoTracks);
+        }
+        if mds_tracks.keys().zip(1..).any(|(&point, n)| point != n) {
+            return Err(MdsError::InvalidTrackNumber);
+        }
+
+        let mut file_indices: BTreeMap<PathBuf, usize> = BTreeMap::new();
+        let mut files = Vec::new();
+        let mut tracks: Vec<DiscTrack> = Vec::new();
+        for (i, &(session, ref track)) in mds_tracks.values().enumerate() {
+            let file_path = if track.file_name.starts_with("*.") {
+                path.with_extension(&track.file_name[2..])
+            } else {
+                path.with_file_name(&track.file_name)
+            };
+            let file = match file_indices.get(&file_path) {
+                Some(&file) => file,
+                None => {
+                    files.push(File::open(&file_path)?);
+                    file_indices.insert(file_path, files.len() - 1);
+                    files.len() - 1
+                }
+            };
+
+            if track.pregap > track.index_one {
+                return Err(MdsError::InvalidTrackLayout);
+            }
+            // The first track additionally covers the two seconds before it
+            let start = if i == 0 { 0 } else { track.index_one - track.pregap };
+            let mut indices = VecMap::new();
+            if start < track.index_one {
+                indices.insert(0, start);
+            }
+            indices.insert(1, track.index_one);
+
+            if let Some(previous) = tracks.last_mut() {
+                if start <= previous.start {
+                    return Err(MdsError::InvalidTrackLayout);
+                }
+                let end = if previous.session == session {
+                    start
+                } else {
+                    // The last track of a session ends at its lead-out, the
+                    // lead-in of the next session isn't part of the image.
+                    let data_end = previous.data.start + previous.data.num_sectors;
+                    let end = lead_outs.get(&previous.session).map_or(data_end, |&x| x.max(data_end));
+                    if end <= previous.start || end > start {
+                        return Err(MdsError::InvalidTrackLayout);
+                    }
+                    end
+                };
+                previous.num_sectors = end - previous.start;
+                if previous.data.num_sectors == 0 {
+                    previous.data.num_sectors = end.saturating_sub(previous.data.start);
+                }
+            }
+
+            // Pregaps aren't stored in the image, the data starts at index 01
+            tracks.push(DiscTrack {
+                track_type: track.track_type,
+                session,
+                flags: track.flags,
+                start,
+                num_sectors: 0,
+                indices,
+                data: TrackData {
+                    file,
+                    start: track.index_one,
+                    num_sectors: track.length.unwrap_or(0),
+                    offset: track.offset,
+                    sector_size: track.sector_size as u32,
+                    format: track.format,
+                    subchannel: if track.has_subchannel {
+                        Some(SubchannelData {
+                            file,
+                            offset: track.offset + 2352,
+                            stride: track.sector_size as u64,
+                            layout: SubchannelLayout::Interleaved,
+                        })
+                    } else {
+                        None
+                    },
+                },
+            });
+        }
+
+        let last_track = tracks.last_mut().unwrap();
+        let data_end = last_track.data.start + last_track.data.num_sectors;
+        let end = lead_outs.get(&last_track.session).map_or(data_end, |&x| x.max(data_end));
+        if end <= last_track.start {
+            return Err(MdsError::InvalidTrackLayout);
+        }
+        last_track.num_sectors = end - last_track.start;
+        if last_track.data.num_sectors == 0 {
+            last_track.data.num_sectors = end.saturating_sub(last_track.data.start);
+        }
+
+        let sidecars = options.load_sidecars(path)?;
+
+        Ok(MdsImage {
+            disc: Disc::new(files, tracks, sidecars),
+        })
+    }
+
+    /// Returns whether the image contains subchannel data for all tracks
+    /// (i.e. uses 2448 byte sectors throughout).
+    pub fn has_subchannel_data(&self) -> bool {
+        self.disc.has_subchannel_data()
+    }
+}
+
+crate::disc::forward_image_to_disc!(MdsImage);
+
+#[cfg(test)]
+mod tests {
+    use crate::mds::*;
+    use crate::{Event, Image};
+
+    // (point, mode, sector size, start sector, offset in the MDF file, pregap,
+    // length) of a track
+    type TrackSpec = (u8, u8, u16, u32, u64, u32, u32);
+
+    // Builds a descriptor from the lead-out (as MSF) and tracks of each
+    // session, all stored in the `.mdf` file.
+    fn build_mds(sessions: &[([u8; 3], &[TrackSpec])]) -> Vec<u8> {
+        let num_tracks: usize = sessions.iter().map(|x| x.1.len()).sum();
+        let mut mds = vec![0u8; HEADER_LEN];
+        mds[..16].copy_from_slice(MDS_MAGIC);
+        mds[16] = 1;
+        mds[17] = 5;
+        mds[20..22].copy_from_slice(&(sessions.len() as u16).to_le_bytes());
+        mds[80..84].copy_from_slice(&(HEADER_LEN as u32).to_le_bytes());
+
+        let mut tracks_offset = HEADER_LEN + sessions.len() * SESSION_BLOCK_LEN;
+        let extra_offset = tracks_offset + (sessions.len() + num_tracks) * TRACK_BLOCK_LEN;
+        let footer_offset = extra_offset + num_tracks * 8;
+        let name_offset = footer_offset + 16;
+
+        for (i, (_, tracks)) in sessions.iter().enumerate() {
+            let mut session = vec![0u8; SESSION_BLOCK_LEN];
+            session[8..10].copy_from_slice(&(i as u16 + 1).to_le_bytes());
+            session[10] = tracks.len() as u8 + 1;
+            session[20..24].copy_from_slice(&(tracks_offset as u32).to_le_bytes());
+            mds.extend(session);
+            tracks_offset += (tracks.len() + 1) * TRACK_BLOCK_LEN;
+        }
+
+        let mut extra = 0;
+        for (lead_out_msf, tracks) in sessions {
+            let mut lead_out = vec![0u8; TRACK_BLOCK_LEN];
+            lead_out[4] = 0xa2;
+            lead_out[9..12].copy_from_slice(lead_out_msf);
+            mds.extend(lead_out);
+
+            for &(point, mode, sector_size, start, offset, _, _) in tracks.iter() {
+                let mut block = vec![0u8; TRACK_BLOCK_LEN];
+                block[0] = mode;
+                block[1] = if sector_size == 2448 { 0x08 } else { 0 };
+                block[4] = point;
+                block[0x0c..0x10].copy_from_slice(&((extra_offset + extra * 8) as u32).to_le_bytes());
+                block[0x10..0x12].copy_from_slice(&sector_size.to_le_bytes());
+                block[0x24..0x28].copy_from_slice(&start.to_le_bytes());
+                block[0x28..0x30].copy_from_slice(&offset.to_le_bytes());
+                block[0x30..0x34].copy_from_slice(&1u32.to_le_bytes());
+                block[0x34..0x38].copy_from_slice(&(footer_offset as u32).to_le_bytes());
+                mds.extend(block);
+                extra += 1;
+            }
+        }
+
+        for &(_, _, _, _, _, pregap, length) in sessions.iter().flat_map(|x| x.1.iter()) {
+            mds.extend(pregap.to_le_bytes());
+            mds.extend(length.to_le_bytes());
+        }
+
+        mds.extend((name_offset as u32).to_le_bytes());
+        mds.extend([0u8; 12]);
+        mds.extend(b"*.mdf\0");
+        mds
+    }
+
+    #[test]
+    fn mds_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let mds_path = dir.path().join("test.mds");
+        // A cooked Mode 1 data track of 10 sectors followed by an audio
+        // track with subchannel data, a pregap of 2 and 5 sectors of data
+        std::fs::write(&mds_path, build_mds(&[([0, 2, 17], &[
+            (1, 0xaa, 2048, 0, 0, 150, 10),
+            (2, 0xa9, 2448, 12, 10 * 2048, 2, 5),
+        ])])).unwrap();
+        let mut mdf = Vec::new();
+        for i in 0..10u8 {
+            mdf.extend([i; 2048]);
+        }
+        for i in 0..5u8 {
+            mdf.extend([0x80 + i; 2352]);
+            mdf.extend([0xf0 + i; 96]);
+        }
+        std::fs::write(dir.path().join("test.mdf"), mdf).unwrap();
+
+        let mut image = MdsImage::open(&mds_path).unwrap();
+        assert_eq!(image.num_tracks(), 2);
+        assert!(!image.has_subchannel_data());
+        assert_eq!(image.track_start(2).unwrap(), MsfIndex::from_lba(162).unwrap());
+        assert_eq!(image.lead_out().unwrap(), MsfIndex::from_lba(167).unwrap());
+
+        let mut buf = [0u8; 2352];
+        image.set_location(MsfIndex::from_lba(151).unwrap()).unwrap();
+        image.copy_current_sector(&mut buf).unwrap();
+        assert_eq!(&buf[12..16], &[0x00, 0x02, 0x01, 0x01]);
+        assert!(buf[16..2064].iter().all(|&x| x == 1));
+
+        image.set_location(MsfIndex::from_lba(159).unwrap()).unwrap();
+        assert_eq!(image.advance_position().unwrap(), Some(Event::TrackChange));
+        assert_eq!(image.current_track_type().unwrap(), TrackType::Audio);
+        image.copy_current_sector(&mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 0));
+
+        image.set_location_to_track(2).unwrap();
+        image.advance_position().unwrap();
+        image.copy_current_sector(&mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 0x81));
+        let mut subcode = [0u8; 96];
+        image.copy_current_subchannel(&mut subcode).unwrap();
+        assert!(subcode.iter().all(|&x| x == 0xf1));
+    }
+
+    #[test]
+    fn mds_multisession() {
+        let dir = tempfile::tempdir().unwrap();
+        let mds_path = dir.path().join("test.mds");
+        // A data track of 10 sectors in the first and one of 3 sectors in the
+        // second session, the lead-outs are at LBA 160 and 11553.
+        std::fs::write(&mds_path, build_mds(&[
+            ([0, 2, 10], &[(1, 0xaa, 2048, 0, 0, 150, 10)]),
+            ([2, 34, 3], &[(2, 0xaa, 2048, 11400, 10 * 2048, 150, 3)]),
+        ])).unwrap();
+        let mdf: Vec<u8> = (0..13u8).flat_map(|x| [x; 2048]).collect();
+        std::fs::write(dir.path().join("test.mdf"), mdf).unwrap();
+
+        let mut image = MdsImage::open(&mds_path).unwrap();
+        assert_eq!(image.lead_out().unwrap(), MsfIndex::from_lba(11553).unwrap());
+        let toc = image.toc().unwrap();
+        assert_eq!(toc.sessions.len(), 2);
+        assert_eq!(toc.sessions[0].lead_out, MsfIndex::from_lba(160).unwrap());
+        assert_eq!(toc.sessions[1].lead_out, MsfIndex::from_lba(11553).unwrap());
+        assert_eq!(toc.track(1).unwrap().length, 10);
+        assert_eq!(toc.track(2).unwrap().length, 3);
+
+        // Between the sessions
+        assert!(image.set_location(MsfIndex::from_lba(160).unwrap()).is_err());
+
+        let mut buf = [0u8; 2352];
+        image.set_location(MsfIndex::from_lba(159).unwrap()).unwrap();
+        assert_eq!(image.advance_position().unwrap(), Some(Event::TrackChange));
+        assert_eq!(image.current_global_msf().unwrap(), MsfIndex::from_lba(11400).unwrap());
+        assert_eq!(image.current_index().unwrap(), 0);
+
+        image.set_location_to_track(2).unwrap();
+        image.advance_position().unwrap();
+        image.copy_current_sector(&mut buf).unwrap();
+        assert!(buf[16..2064].iter().all(|&x| x == 11));
+    }
+}
@@ -0,0 +1,60 @@
+// Format independent textual information about a disc and its tracks as
+// returned by `Image::metadata`.
+
+#[cfg(feature = "serde-support")]
+use serde_derive::{Deserialize, Serialize};
+
+
+/// ReplayGain information of an album or track
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde-support", derive(Serialize, Deserialize))]
+pub struct ReplayGain {
+    /// Gain in dB
+    pub gain: Option<f32>,
+    /// Peak amplitude, 1.0 being full scale
+    pub peak: Option<f32>,
+}
+
+/// Metadata of the whole disc.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde-support", derive(Serialize, Deserialize))]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub songwriter: Option<String>,
+    /// Media catalog number (UPC/EAN)
+    pub catalog: Option<String>,
+    pub genre: Option<String>,
+    pub date: Option<String>,
+    /// FreeDB disc ID
+    pub disc_id: Option<String>,
+    pub comment: Option<String>,
+    /// Album gain and peak
+    pub replay_gain: ReplayGain,
+    /// Remaining comments as pairs of their (uppercase) key and value
+    pub remarks: Vec<(String, String)>,
+    /// Metadata of all tracks, in order
+    pub tracks: Vec<TrackMetadata>,
+}
+
+/// Metadata of a single track.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde-support", derive(Serialize, Deserialize))]
+pub struct TrackMetadata {
+    pub number: u8,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub songwriter: Option<String>,
+    pub isrc: Option<String>,
+    /// Track gain and peak
+    pub replay_gain: ReplayGain,
+    /// Comments as pairs of their (uppercase) key and value
+    pub remarks: Vec<(String, String)>,
+}
+
+impl Metadata {
+    /// Returns the metadata of the track with the given number.
+    pub fn track(&self, number: u8) -> Option<&TrackMetadata> {
+        self.tracks.iter().find(|x| x.number == number)
+    }
+}
@@ -0,0 +1,532 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use log::debug;
+
+use thiserror::Error;
+
+use vec_map::VecMap;
+
+use crate::disc::{Disc, DiscTrack, SubchannelData, SubchannelLayout, TrackData};
+use crate::sector::SectorFormat;
+use crate::{MsfIndex, OpenOptions, TrackFlags, TrackType};
+
+
+#[derive(Debug, Error)]
+pub enum NrgError {
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    SidecarError(#[from] crate::SidecarError),
+    #[error("Input file does not seem like an NRG file (no footer found)")]
+    NotAnNrgFile,
+    #[error("NRG file is truncated or contains an invalid chunk")]
+    Truncated,
+    #[error("Missing {0} chunk in NRG file")]
+    MissingChunk(&'static str),
+    #[error("Track-at-once NRG images are not supported")]
+    TrackAtOnce,
+    #[error("Unsupported sector size {0} for track mode {1:#x} in NRG file")]
+    UnsupportedSectorFormat(u16, u8),
+    #[error("Invalid track number in NRG file")]
+    InvalidTrackNumber,
+    #[error("Invalid track layout in NRG file")]
+    InvalidTrackLayout,
+    #[error("No tracks in NRG file")]
+    NoTracks,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum NrgVersion {
+    // "NERO" footer, 32 bit offsets
+    V1,
+    // "NER5" footer, 64 bit offsets
+    V2,
+}
+
+// Looks for a footer at the end of `file` and returns the version and the
+// offset of the first chunk
+fn read_footer<F>(file: &mut F) -> std::io::Result<Option<(NrgVersion, u64)>>
+    where F: Read + Seek
+{
+    let len = file.seek(SeekFrom::End(0))?;
+    if len < 12 {
+        return Ok(None);
+    }
+    let mut footer = [0u8; 12];
+    file.seek(SeekFrom::Start(len - 12))?;
+    file.read_exact(&mut footer)?;
+    if &footer[0..4] == b"NER5" {
+        Ok(Some((NrgVersion::V2, u64::from_be_bytes(footer[4..12].try_into().unwrap()))))
+    } else if &footer[4..8] == b"NERO" {
+        Ok(Some((NrgVersion::V1, u32::from_be_bytes(footer[8..12].try_into().unwrap()) as u64)))
+    } else {
+        Ok(None)
+    }
+}
+
+pub(crate) fn has_nrg_footer(file: &mut File) -> std::io::Result<bool> {
+    Ok(read_footer(file)?.is_some())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, NrgError> {
+    let bytes = data.get(offset..offset + 4).ok_or(NrgError::Truncated)?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Result<u64, NrgError> {
+    let bytes = data.get(offset..offset + 8).ok_or(NrgError::Truncated)?;
+    Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn from_bcd(x: u8) -> u8 {
+    (x >> 4) * 10 + (x & 0x0f)
+}
+
+// Positions of a track's indices taken from the CUEX/CUES chunk
+#[derive(Debug, Default)]
+struct CueEntries {
+    // Track number -> index number -> absolute LBA
+    tracks: BTreeMap<u8, VecMap<u32>>,
+    // Track number -> flags from the control field of its entries
+    flags: BTreeMap<u8, TrackFlags>,
+    // Session -> absolute LBA of its lead-out, each session has its own chunk
+    lead_outs: BTreeMap<u8, u32>,
+    // Number of chunks parsed so far
+    num_chunks: u8,
+}
+
+impl CueEntries {
+    fn parse(&mut self, data: &[u8], version: NrgVersion) -> Result<(), NrgError> {
+        self.num_chunks += 1;
+        for entry in data.chunks_exact(8) {
+            let track = entry[1];
+            let index = from_bcd(entry[2]) as usize;
+            let lba = match version {
+                NrgVersion::V2 => {
+                    let lba = i32::from_be_bytes(entry[4..8].try_into().unwrap()) as i64 + 150;
+                    if lba < 0 {
+                        return Err(NrgError::InvalidTrackLayout);
+                    }
+                    lba as u32
+                }
+                NrgVersion::V1 => MsfIndex::from_bcd_values(entry[5], entry[6], entry[7])
+                    .map_err(|_| NrgError::InvalidTrackLayout)?
+                    .to_lba(),
+            };
+            if track == 0xaa {
+                self.lead_outs.insert(self.num_chunks, lba);
+            } else if track != 0 {
+                self.tracks.entry(from_bcd(track)).or_default().insert(index, lba);
+                // Control in the upper, ADR in the lower nibble
+                self.flags.insert(from_bcd(track), TrackFlags::from_control(entry[0] >> 4));
+            }
+        }
+        Ok(())
+    }
+}
+
+struct DaoTrack {
+    track_type: TrackType,
+    format: SectorFormat,
+    has_subchannel: bool,
+    sector_size: u16,
+    pregap_offset: u64,
+    start_offset: u64,
+    end_offset: u64,
+}
+
+fn parse_dao_chunk(data: &[u8], version: NrgVersion, dao_tracks: &mut BTreeMap<u8, DaoTrack>) -> Result<(), NrgError> {
+    const HEADER_LEN: usize = 22;
+    let (block_len, offset_len) = match version {
+        NrgVersion::V1 => (30, 4),
+        NrgVersion::V2 => (42, 8),
+    };
+    if data.len() < HEADER_LEN {
+        return Err(NrgError::Truncated);
+    }
+    let first_track = data[20];
+    let last_track = data[21];
+    if first_track == 0 || first_track > last_track {
+        return Err(NrgError::InvalidTrackNumber);
+    }
+
+    for (i, track_no) in (first_track..=last_track).enumerate() {
+        let block = data.get(HEADER_LEN + i * block_len..HEADER_LEN + (i + 1) * block_len)
+            .ok_or(NrgError::Truncated)?;
+        let sector_size = u16::from_be_bytes([block[12], block[13]]);
+        let mode = block[14];
+        let offset = |n: usize| -> Result<u64, NrgError> {
+            match version {
+                NrgVersion::V1 => Ok(u32_at(block, 18 + n * offset_len)? as u64),
+                NrgVersion::V2 => u64_at(block, 18 + n * offset_len),
+            }
+        };
+
+        let track_type = match mode {
+            0x00 | 0x05 | 0x0f => TrackType::Mode1,
+            0x02 | 0x03 | 0x06 | 0x11 => TrackType::Mode2,
+            0x07 | 0x10 => TrackType::Audio,
+            _ => return Err(NrgError::UnsupportedSectorFormat(sector_size, mode)),
+        };
+        let format = match (sector_size, mode) {
+            (2048, 0x00) => SectorFormat::Mode1Cooked,
+            (2048, 0x02) => SectorFormat::Mode2Form1Cooked,
+            (2336, 0x03) => SectorFormat::Mode2Cooked,
+            (2352, _) | (2448, _) => SectorFormat::Raw,
+            _ => return Err(NrgError::UnsupportedSectorFormat(sector_size, mode)),
+        };
+
+        dao_tracks.insert(track_no, DaoTrack {
+            track_type,
+            format,
+            has_subchannel: sector_size == 2448,
+            sector_size,
+            pregap_offset: offset(0)?,
+            start_offset: offset(1)?,
+            end_offset: offset(2)?,
+        });
+    }
+    Ok(())
+}
+
+/// An image in Nero Burning ROM format (`.nrg`), disc-at-once images with
+/// v1 (`NERO`) and v2 (`NER5`) footers are supported.
+pub struct NrgImage {
+    disc: Disc,
+}
+
+impl NrgImage {
+    pub fn open<P>(path: P) -> Result<NrgImage, NrgError>
+        where P: AsRef<Path>
+    {
+        Self::_open(path.as_ref(), &OpenOptions::new())
+    }
+
+    /// Like `open`, but loads the side-car files according to `options`.
+    pub fn open_with_options<P>(path: P, options: &OpenOptions) -> Result<NrgImage, NrgError>
+        where P: AsRef<Path>
+    {
+        Self::_open(path.as_ref(), options)
+    }
+
+    fn _open(path: &Path, options: &OpenOptions) -> Result<NrgImage, NrgError> {
+        let mut file = File::open(path)?;
+        let (version, chunks_offset) = read_footer(&mut file)?.ok_or(NrgError::NotAnNrgFile)?;
+        let len = file.seek(SeekFrom::End(0))?;
+        if chunks_offset >= len {
+            return Err(NrgError::Truncated);
+        }
+        let mut chunks = Vec::new();
+        file.seek(SeekFrom::Start(chunks_offset))?;
+        file.by_ref().take(len - chunks_offset).read_to_end(&mut chunks)?;
+
+        let mut cue_entries = CueEntries::default();
+        let mut found_cue_chunk = false;
+        let mut dao_tracks = BTreeMap::new();
+        // Number of tracks in each session
+        let mut session_tracks = Vec::new();
+        let mut pos = 0;
+        while pos + 8 <= chunks.len() {
+            let id = &chunks[pos..pos + 4];
+            let size = u32_at(&chunks, pos + 4)? as usize;
+            let data = chunks.get(pos + 8..pos + 8 + size).ok_or(NrgError::Truncated)?;
+            debug!("NRG chunk {} with {} bytes", String::from_utf8_lossy(id), size);
+            match id {
+                b"CUEX" | b"CUES" => {
+                    cue_entries.parse(data, version)?;
+                    found_cue_chunk = true;
+                }
+                b"DAOX" | b"DAOI" => parse_dao_chunk(data, version, &mut dao_tracks)?,
+                b"SINF" => session_tracks.push(u32_at(data, 0)?),
+                b"ETNF" | b"ETN2" => return Err(NrgError::TrackAtOnce),
+                b"END!" => break,
+                _ => {}
+            }
+            pos += 8 + size;
+        }
+        debug!("NRG image with {} sessions", session_tracks.len());
+
+        if !found_cue_chunk {
+            return Err(NrgError::MissingChunk("CUEX"));
+        }
+        if dao_tracks.is_empty() {
+            return Err(NrgError::NoTracks);
+        }
+        if dao_tracks.keys().zip(1..).any(|(&track, n)| track != n) {
+            return Err(NrgError::InvalidTrackNumber);
+        }
+
+        // Images without session info are treated as single session
+        let session_for_track = |track_no: u8| {
+            let mut session_end = 0;
+            for (i, &num_tracks) in session_tracks.iter().enumerate() {
+                session_end += num_tracks;
+                if track_no as u32 <= session_end {
+                    return i as u8 + 1;
+                }
+            }
+            session_tracks.len().max(1) as u8
+        };
+
+        let mut tracks: Vec<DiscTrack> = Vec::new();
+        for (&track_no, dao) in dao_tracks.iter() {
+            let session = session_for_track(track_no);
+            let mut indices = cue_entries.tracks.remove(&track_no)
+                .ok_or(NrgError::MissingChunk("CUEX"))?;
+            let index_one = *indices.get(1).ok_or(NrgError::InvalidTrackLayout)?;
+            if dao.sector_size == 0 || dao.pregap_offset > dao.start_offset || dao.start_offset > dao.end_offset {
+                return Err(NrgError::InvalidTrackLayout);
+            }
+            let stored_pregap = ((dao.start_offset - dao.pregap_offset) / dao.sector_size as u64) as u32;
+            let stored_sectors = ((dao.end_offset - dao.pregap_offset) / dao.sector_size as u64) as u32;
+            if stored_pregap > index_one {
+                return Err(NrgError::InvalidTrackLayout);
+            }
+
+            // The first track additionally covers the two seconds before it
+            let start = if track_no == 1 {
+                0
+            } else {
+                *indices.get(0).unwrap_or(&index_one)
+            };
+            if start < index_one && !indices.contains_key(0) {
+                indices.insert(0, start);
+            }
+
+            if let Some(previous) = tracks.last_mut() {
+                if start <= previous.start {
+                    return Err(NrgError::InvalidTrackLayout);
+                }
+                previous.num_sectors = if previous.session == session {
+                    start - previous.start
+                } else {
+                    // The last track of a session ends at its lead-out, the
+                    // lead-in of the next session isn't part of the image.
+                    let data_end = previous.data.start + previous.data.num_sectors;
+                    let end = cue_entries.lead_outs.get(&previous.session).map_or(data_end, |&x| x.max(data_end));
+                    if end <= previous.start || end > start {
+                        return Err(NrgError::InvalidTrackLayout);
+                    }
+                    end - previous.start
+                };
+            }
+
+            tracks.push(DiscTrack {
+                track_type: dao.track_type,
+                session,
+                flags: cue_entries.flags.get(&track_no).copied().unwrap_or_default(),
+                start,
+                num_sectors: 0,
+                indices,
+                data: TrackData {
+                    file: 0,
+                    start: index_one - stored_pregap,
+                    num_sectors: stored_sectors,
+                    offset: dao.pregap_offset,
+                    sector_size: dao.sector_size as u32,
+                    format: dao.format,
+                    subchannel: if dao.has_subchannel {
+                        Some(SubchannelData {
+                            file: 0,
+                            offset: dao.pregap_offset + 2352,
+                            stride: dao.sector_size as u64,
+                            layout: SubchannelLayout::Interleaved,
+                        })
+                    } else {
+                        None
+                    },
+                },
+            });
+        }
+
+        let last_track = tracks.last_mut().unwrap();
+        let data_end = last_track.data.start + last_track.data.num_sectors;
+        let end = cue_entries.lead_outs.get(&last_track.session).map_or(data_end, |&x| x.max(data_end));
+        if end <= last_track.start {
+            return Err(NrgError::InvalidTrackLayout);
+        }
+        last_track.num_sectors = end - last_track.start;
+
+        let sidecars = options.load_sidecars(path)?;
+
+        Ok(NrgImage {
+            disc: Disc::new(vec![file], tracks, sidecars),
+        })
+    }
+
+    /// Returns whether the image contains subchannel data for all tracks
+    /// (i.e. uses 2448 byte sectors throughout).
+    pub fn has_subchannel_data(&self) -> bool {
+        self.disc.has_subchannel_data()
+    }
+}
+
+crate::disc::forward_image_to_disc!(NrgImage);
+
+#[cfg(test)]
+mod tests {
+    use crate::nrg::*;
+    use crate::{Event, Image};
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend((data.len() as u32).to_be_bytes());
+        chunk.extend(data);
+        chunk
+    }
+
+    fn cue_entry(adr_ctl: u8, track: u8, index: u8, lba: i32) -> [u8; 8] {
+        let lba = lba.to_be_bytes();
+        [adr_ctl, track, index, 0, lba[0], lba[1], lba[2], lba[3]]
+    }
+
+    fn dao_block(sector_size: u16, mode: u8, offsets: [u64; 3]) -> Vec<u8> {
+        let mut block = vec![0u8; 12];
+        block.extend(sector_size.to_be_bytes());
+        block.extend([mode, 0, 0, 0]);
+        for offset in offsets.iter() {
+            block.extend(offset.to_be_bytes());
+        }
+        block
+    }
+
+    // A v2 image with a cooked Mode 1 track of 4 sectors and an audio track
+    // with 2 sectors of pregap and 3 sectors of data
+    fn build_nrg() -> Vec<u8> {
+        let mut nrg = Vec::new();
+        for i in 0..4u8 {
+            nrg.extend([i; 2048]);
+        }
+        let track2_offset = nrg.len() as u64;
+        for i in 0..5u8 {
+            nrg.extend([0x80 + i; 2352]);
+        }
+        let end = nrg.len() as u64;
+        let chunks_offset = nrg.len() as u64;
+
+        let mut cuex = Vec::new();
+        for entry in [
+            cue_entry(0x41, 0x00, 0x00, -150),
+            cue_entry(0x41, 0x01, 0x00, -150),
+            cue_entry(0x41, 0x01, 0x01, 0),
+            cue_entry(0x01, 0x02, 0x00, 4),
+            cue_entry(0x01, 0x02, 0x01, 6),
+            cue_entry(0x01, 0xaa, 0x01, 9),
+        ] {
+            cuex.extend(entry);
+        }
+        nrg.extend(chunk(b"CUEX", &cuex));
+
+        let mut daox = vec![0u8; 20];
+        daox.extend([1, 2]);
+        daox.extend(dao_block(2048, 0x00, [0, 0, track2_offset]));
+        daox.extend(dao_block(2352, 0x07, [track2_offset, track2_offset + 2 * 2352, end]));
+        nrg.extend(chunk(b"DAOX", &daox));
+        nrg.extend(chunk(b"SINF", &2u32.to_be_bytes()));
+        nrg.extend(chunk(b"END!", &[]));
+
+        nrg.extend(b"NER5");
+        nrg.extend(chunks_offset.to_be_bytes());
+        nrg
+    }
+
+    // A v2 image with two sessions, each consisting of one cooked Mode 1
+    // track. The first one has 4 sectors, the second one 3 sectors and a
+    // pregap that isn't stored in the image.
+    fn build_multisession_nrg() -> Vec<u8> {
+        let mut nrg: Vec<u8> = (0..7u8).flat_map(|x| [x; 2048]).collect();
+        let chunks_offset = nrg.len() as u64;
+
+        // (track, cue entries, first and end sector in the image)
+        let sessions = [
+            (1u8, [
+                cue_entry(0x41, 0x01, 0x00, -150),
+                cue_entry(0x41, 0x01, 0x01, 0),
+                cue_entry(0x41, 0xaa, 0x01, 4),
+            ], 0u64, 4u64),
+            (2, [
+                cue_entry(0x41, 0x02, 0x00, 11250),
+                cue_entry(0x41, 0x02, 0x01, 11400),
+                cue_entry(0x41, 0xaa, 0x01, 11403),
+            ], 4, 7),
+        ];
+        for (track, entries, start, end) in sessions {
+            nrg.extend(chunk(b"CUEX", &entries.concat()));
+            let mut daox = vec![0u8; 20];
+            daox.extend([track, track]);
+            daox.extend(dao_block(2048, 0x00, [start * 2048, start * 2048, end * 2048]));
+            nrg.extend(chunk(b"DAOX", &daox));
+            nrg.extend(chunk(b"SINF", &1u32.to_be_bytes()));
+        }
+        nrg.extend(chunk(b"END!", &[]));
+
+        nrg.extend(b"NER5");
+        nrg.extend(chunks_offset.to_be_bytes());
+        nrg
+    }
+
+    #[test]
+    fn nrg_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.nrg");
+        std::fs::write(&path, build_nrg()).unwrap();
+
+        let mut image = NrgImage::open(&path).unwrap();
+        assert_eq!(image.num_tracks(), 2);
+        assert_eq!(image.first_track_type(), TrackType::Mode1);
+        assert_eq!(image.track_start(1).unwrap(), MsfIndex::from_lba(150).unwrap());
+        assert_eq!(image.track_start(2).unwrap(), MsfIndex::from_lba(156).unwrap());
+        assert_eq!(image.lead_out().unwrap(), MsfIndex::from_lba(159).unwrap());
+
+        let mut buf = [0u8; 2352];
+        image.copy_current_sector(&mut buf).unwrap();
+        assert_eq!(&buf[12..16], &[0x00, 0x02, 0x00, 0x01]);
+        assert!(buf[16..2064].iter().all(|&x| x == 0));
+
+        image.set_location(MsfIndex::from_lba(153).unwrap()).unwrap();
+        assert_eq!(image.advance_position().unwrap(), Some(Event::TrackChange));
+        assert_eq!(image.current_index().unwrap(), 0);
+        image.copy_current_sector(&mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 0x80));
+
+        image.set_location_to_track(2).unwrap();
+        image.copy_current_sector(&mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 0x82));
+    }
+
+    #[test]
+    fn nrg_multisession() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.nrg");
+        std::fs::write(&path, build_multisession_nrg()).unwrap();
+
+        let mut image = NrgImage::open(&path).unwrap();
+        assert_eq!(image.num_tracks(), 2);
+        assert_eq!(image.lead_out().unwrap(), MsfIndex::from_lba(11553).unwrap());
+        let toc = image.toc().unwrap();
+        assert_eq!(toc.sessions.len(), 2);
+        assert_eq!(toc.sessions[0].lead_out, MsfIndex::from_lba(154).unwrap());
+        assert_eq!(toc.sessions[1].lead_out, MsfIndex::from_lba(11553).unwrap());
+        assert_eq!(toc.track(1).unwrap().length, 4);
+        assert_eq!(toc.track(2).unwrap().length, 3);
+
+        // Between the sessions
+        assert!(image.set_location(MsfIndex::from_lba(154).unwrap()).is_err());
+
+        let mut buf = [0u8; 2352];
+        image.set_location(MsfIndex::from_lba(153).unwrap()).unwrap();
+        assert_eq!(image.advance_position().unwrap(), Some(Event::TrackChange));
+        assert_eq!(image.current_global_msf().unwrap(), MsfIndex::from_lba(11400).unwrap());
+        assert_eq!(image.current_index().unwrap(), 0);
+        image.copy_current_sector(&mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 0));
+
+        image.set_location_to_track(2).unwrap();
+        image.copy_current_sector(&mut buf).unwrap();
+        assert!(buf[16..2064].iter().all(|&x| x == 4));
+    }
+}
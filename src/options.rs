@@ -0,0 +1,252 @@
+// Options for opening images, mostly concerning the side-car files loaded
+// alongside them.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::cdtext::CdText;
+use crate::sbi::{self, SbiRecords};
+use crate::{cdi, cue, clonecd, iso, mds, nrg};
+#[cfg(feature = "chd")]
+use crate::chd;
+use crate::{Image, ImageError};
+use crate::{info, warn};
+
+use thiserror::Error;
+
+
+/// Kind of a side-car file loaded alongside an image
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SidecarKind {
+    /// Replacement Q subchannel data (LibCrypt)
+    Sbi,
+    /// Replacement Q subchannel data including the CRC (LibCrypt)
+    Lsd,
+    /// Deinterleaved subchannel data of a cuesheet
+    Sub,
+    /// Binary CD-TEXT data
+    CdText,
+}
+
+/// Side-car file loaded alongside an image
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sidecar {
+    pub kind: SidecarKind,
+    pub path: PathBuf,
+}
+
+/// Failure to load a side-car file in strict mode
+#[derive(Debug, Error)]
+#[error("Failed to load side-car file {path:?}")]
+pub struct SidecarError {
+    pub kind: SidecarKind,
+    pub path: PathBuf,
+    pub source: Box<dyn std::error::Error + Send + Sync>,
+}
+
+// Data from the side-car files loaded for an image
+#[derive(Default)]
+pub(crate) struct Sidecars {
+    pub sbi_records: Option<SbiRecords>,
+    pub cd_text: Option<CdText>,
+    pub loaded: Vec<Sidecar>,
+    pub failed: Vec<(Sidecar, String)>,
+}
+
+impl Sidecars {
+    // Records the result of loading the side-car file at `path`, which is
+    // only an error in strict mode. Otherwise, failures are kept in `failed`.
+    pub(crate) fn add<T, E>(&mut self, result: Result<T, E>, kind: SidecarKind, path: PathBuf, strict: bool)
+        -> Result<Option<T>, SidecarError>
+        where E: std::error::Error + Send + Sync + 'static
+    {
+        match result {
+            Ok(x) => {
+                info!("Loaded {:?} file {:?}", kind, path);
+                self.loaded.push(Sidecar { kind, path });
+                Ok(Some(x))
+            }
+            Err(e) if strict => Err(SidecarError { kind, path, source: Box::new(e) }),
+            Err(e) => {
+                warn!("Failed to load {:?} file {:?}: {}", kind, path, e);
+                self.failed.push((Sidecar { kind, path }, e.to_string()));
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Options for opening an image. By default, an SBI or LSD file and a `.cdt`
+/// CD-TEXT file with the same name as the image are loaded if there are any,
+/// and failing to parse them only logs a warning.
+#[derive(Clone, Debug)]
+pub struct OpenOptions {
+    detect_sidecars: bool,
+    sbi_path: Option<PathBuf>,
+    strict: bool,
+    lenient: bool,
+}
+
+impl Default for OpenOptions {
+    fn default() -> OpenOptions {
+        OpenOptions {
+            detect_sidecars: true,
+            sbi_path: None,
+            strict: false,
+            lenient: false,
+        }
+    }
+}
+
+impl OpenOptions {
+    pub fn new() -> OpenOptions {
+        OpenOptions::default()
+    }
+
+    /// Whether to look for side-car files next to the image.
+    pub fn detect_sidecars(&mut self, detect: bool) -> &mut OpenOptions {
+        self.detect_sidecars = detect;
+        self
+    }
+
+    /// Loads the replacement Q subchannel data from the file at `path`
+    /// instead of looking for one next to the image. It's parsed as an LSD
+    /// file if its extension is `lsd`, as an SBI file otherwise.
+    pub fn sbi_path<P>(&mut self, path: P) -> &mut OpenOptions
+        where P: Into<PathBuf>
+    {
+        self.sbi_path = Some(path.into());
+        self
+    }
+
+    /// Whether failing to load a side-car file makes opening the image fail.
+    pub fn strict(&mut self, strict: bool) -> &mut OpenOptions {
+        self.strict = strict;
+        self
+    }
+
+    /// Whether to accept cuesheets deviating from the format, logging a
+    /// warning for each deviation. `Cuesheet::open_lenient` returns the
+    /// warnings instead.
+    pub fn lenient(&mut self, lenient: bool) -> &mut OpenOptions {
+        self.lenient = lenient;
+        self
+    }
+
+    pub(crate) fn is_lenient(&self) -> bool {
+        self.lenient
+    }
+
+    pub(crate) fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Opens the image at `path`, detecting its format.
+    pub fn open<P>(&self, path: P) -> Result<Box<dyn Image>, ImageError>
+        where P: AsRef<Path>
+    {
+        let path = path.as_ref();
+        let mut file = File::open(path)?;
+        let mut magic = [0u8; 16];
+        let magic_len = file.read(&mut magic)?;
+        let magic = &magic[..magic_len];
+
+        #[cfg(feature = "chd")] {
+            if magic.starts_with(b"MComprHD") {
+                return Ok(Box::new(chd::ChdImage::open_with_options(path, self)?));
+            }
+        }
+
+        if magic == mds::MDS_MAGIC {
+            return Ok(Box::new(mds::MdsImage::open_with_options(path, self)?));
+        }
+
+        if nrg::has_nrg_footer(&mut file)? {
+            return Ok(Box::new(nrg::NrgImage::open_with_options(path, self)?));
+        }
+
+        if cdi::has_cdi_trailer(&mut file)? {
+            return Ok(Box::new(cdi::CdiImage::open_with_options(path, self)?));
+        }
+
+        if let Some(ext) = path.extension() {
+            match ext.to_string_lossy().to_lowercase().as_str() {
+                "cue" => return Ok(Box::new(cue::Cuesheet::open_with_options(path, self)?)),
+                "ccd" => return Ok(Box::new(clonecd::CloneCdImage::open_with_options(path, self)?)),
+                "iso" => return Ok(Box::new(iso::IsoImage::open_with_options(path, self)?)),
+                _ => {}
+            }
+        }
+
+        Err(ImageError::UnsupportedFormat)
+    }
+
+    // Returns the path of the side-car file with the given extension next to
+    // the image at `image_path` if detection is enabled and it exists
+    pub(crate) fn detected_sidecar(&self, image_path: &Path, extension: &str) -> Option<PathBuf> {
+        let path = image_path.with_extension(extension);
+        if self.detect_sidecars && path.exists() {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    // Loads the SBI or LSD and CD-TEXT files for the image at `image_path`
+    pub(crate) fn load_sidecars(&self, image_path: &Path) -> Result<Sidecars, SidecarError> {
+        let mut sidecars = Sidecars::default();
+
+        let sbi_path = self.sbi_path.clone()
+            .or_else(|| self.detected_sidecar(image_path, "sbi"))
+            .or_else(|| self.detected_sidecar(image_path, "lsd"));
+        if let Some(path) = sbi_path {
+            let is_lsd = path.extension().is_some_and(|x| x.eq_ignore_ascii_case("lsd"));
+            let (result, kind) = if is_lsd {
+                (sbi::load_lsd_file(&path), SidecarKind::Lsd)
+            } else {
+                (sbi::load_sbi_file(&path), SidecarKind::Sbi)
+            };
+            sidecars.sbi_records = sidecars.add(result, kind, path, self.strict)?;
+        }
+
+        if let Some(path) = self.detected_sidecar(image_path, "cdt") {
+            sidecars.cd_text = sidecars.add(CdText::load(&path), SidecarKind::CdText, path, self.strict)?;
+        }
+
+        Ok(sidecars)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sidecar_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let cue_path = dir.path().join("test.cue");
+        std::fs::write(&cue_path, "FILE \"test.bin\" BINARY\n  TRACK 01 MODE2/2352\n    INDEX 01 00:00:00\n").unwrap();
+        std::fs::write(dir.path().join("test.bin"), [0u8; 2352]).unwrap();
+        std::fs::write(dir.path().join("test.sbi"), b"broken").unwrap();
+        let other_sbi = dir.path().join("other.sbi");
+        std::fs::write(&other_sbi, b"SBI\0\x00\x02\x00\x03\x00\x02\x01").unwrap();
+
+        let image = OpenOptions::new().open(&cue_path).unwrap();
+        assert!(image.sidecars().is_empty());
+        assert!(image.sbi_records().is_none());
+        let failed = image.failed_sidecars();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, Sidecar { kind: SidecarKind::Sbi, path: dir.path().join("test.sbi") });
+
+        let result = OpenOptions::new().strict(true).open(&cue_path);
+        assert!(matches!(result, Err(ImageError::CueError(cue::CueError::SidecarError(_)))));
+        let image = OpenOptions::new().strict(true).detect_sidecars(false).open(&cue_path).unwrap();
+        assert!(image.sidecars().is_empty());
+        assert!(image.failed_sidecars().is_empty());
+
+        let image = OpenOptions::new().strict(true).sbi_path(&other_sbi).open(&cue_path).unwrap();
+        assert_eq!(image.sidecars(), &[Sidecar { kind: SidecarKind::Sbi, path: other_sbi }]);
+        assert_eq!(image.sbi_records().unwrap().len(), 1);
+    }
+}
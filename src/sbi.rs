@@ -1,10 +1,17 @@
-use std::collections::BTreeSet;
+// Parsing and writing of SBI and LSD files, which store the Q subchannel
+// data of the sectors of a disc that differ from what would be expected from
+// their position, as used by the LibCrypt copy protection.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
 use std::fs::File;
 use std::path::Path;
-use std::io::Read;
+use std::io::{self, Read, Write};
 
 use crate::index::{MsfIndex, MsfIndexError};
-use crate::debug;
+use crate::subchannel;
+use crate::{Event, Image, ImageError};
+use crate::{debug, error};
 
 use thiserror::Error;
 
@@ -19,10 +26,81 @@ pub enum SbiParseError {
     InvalidMode,
     #[error("Input file does not seem like an SBI file (Magic doesn't match)")]
     NotAnSbiFile,
+    #[error("File ends in the middle of a record")]
+    Truncated,
+}
+
+
+/// Q subchannel data of a sector as stored in an SBI or LSD file
+#[derive(Clone, Debug, PartialEq)]
+pub enum SbiRecord {
+    /// Mode 0: Marks the Q subchannel of the sector as invalid without
+    /// replacing any of it. The 3 data bytes are kept as stored.
+    Unpatched([u8; 3]),
+    /// Mode 1: The first 10 bytes of the Q subchannel frame (everything but
+    /// the CRC)
+    Full([u8; 10]),
+    /// Mode 2: Relative MSF, BCD encoded
+    Relative([u8; 3]),
+    /// Mode 3: Absolute MSF, BCD encoded
+    Absolute([u8; 3]),
+    /// The whole Q subchannel frame including the CRC, as stored in LSD
+    /// files
+    Lsd([u8; 12]),
+}
+
+impl SbiRecord {
+    /// Returns the mode of the record as stored in SBI files. LSD records
+    /// replace the whole frame and count as mode 1.
+    pub fn mode(&self) -> u8 {
+        match self {
+            SbiRecord::Unpatched(_) => 0,
+            SbiRecord::Full(_) | SbiRecord::Lsd(_) => 1,
+            SbiRecord::Relative(_) => 2,
+            SbiRecord::Absolute(_) => 3,
+        }
+    }
+
+    /// Returns the Q subchannel bytes stored in the record.
+    pub fn data(&self) -> &[u8] {
+        match self {
+            SbiRecord::Full(data) => data,
+            SbiRecord::Unpatched(data) => data,
+            SbiRecord::Relative(msf) | SbiRecord::Absolute(msf) => msf,
+            SbiRecord::Lsd(q) => q,
+        }
+    }
+
+    // Patches the Q subchannel frame `q`. SBI files don't store the CRC, so
+    // it gets recomputed for their records.
+    pub(crate) fn apply(&self, q: &mut [u8]) {
+        match self {
+            SbiRecord::Unpatched(_) => return,
+            SbiRecord::Lsd(data) => {
+                q.copy_from_slice(data);
+                return;
+            }
+            SbiRecord::Full(data) => q[..10].copy_from_slice(data),
+            SbiRecord::Relative(msf) => q[3..6].copy_from_slice(msf),
+            SbiRecord::Absolute(msf) => q[7..10].copy_from_slice(msf),
+        }
+        let crc = subchannel::q_crc(&q[..10]);
+        q[10..12].copy_from_slice(&crc.to_be_bytes());
+    }
 }
 
+/// SBI records by the absolute LBA of their sector
+pub type SbiRecords = BTreeMap<u32, SbiRecord>;
 
-pub fn load_sbi_file<P>(path: P) -> Result<BTreeSet<u32>, SbiParseError>
+/// File format to create records for in `scan_image`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PatchFormat {
+    Sbi,
+    Lsd,
+}
+
+/// Loads the records of the SBI file at `path`.
+pub fn load_sbi_file<P>(path: P) -> Result<SbiRecords, SbiParseError>
         where P: AsRef<Path>
 {
     let mut sbi_file = File::open(path)?;
@@ -33,7 +111,7 @@ pub fn load_sbi_file<P>(path: P) -> Result<BTreeSet<u32>, SbiParseError>
         return Err(SbiParseError::NotAnSbiFile);
     }
 
-    let mut invalid_subq_lbas = BTreeSet::new();
+    let mut records = BTreeMap::new();
 
     let mut index = 4;
     while index + 3 < sbi_data.len() {
@@ -44,17 +122,265 @@ pub fn load_sbi_file<P>(path: P) -> Result<BTreeSet<u32>, SbiParseError>
         debug!("m: {}, s: {}, f: {}", m, s, f);
         let msf = MsfIndex::from_bcd_values(m, s, f)?;
         let lba = msf.to_lba();
-        invalid_subq_lbas.insert(lba);
 
         let mode = sbi_data[index + 3];
-        if mode == 1 {
-            index += 4 + 10;
-        } else if mode <= 3 {
-            index += 4 + 3;
-        } else {
-            return Err(SbiParseError::InvalidMode);
+        let data = &sbi_data[index + 4..];
+        let (record, len) = match mode {
+            0 if data.len() >= 3 => (SbiRecord::Unpatched(data[..3].try_into().unwrap()), 3),
+            1 if data.len() >= 10 => (SbiRecord::Full(data[..10].try_into().unwrap()), 10),
+            2 if data.len() >= 3 => (SbiRecord::Relative(data[..3].try_into().unwrap()), 3),
+            3 if data.len() >= 3 => (SbiRecord::Absolute(data[..3].try_into().unwrap()), 3),
+            0..=3 => return Err(SbiParseError::Truncated),
+            _ => return Err(SbiParseError::InvalidMode),
+        };
+        records.insert(lba, record);
+        index += 4 + len;
+    }
+
+    Ok(records)
+}
+
+/// Loads the records of the LSD file at `path`.
+pub fn load_lsd_file<P>(path: P) -> Result<SbiRecords, SbiParseError>
+        where P: AsRef<Path>
+{
+    let mut lsd_data = Vec::new();
+    File::open(path)?.read_to_end(&mut lsd_data)?;
+
+    // Each record consists of the BCD encoded MSF and the Q subchannel frame
+    if lsd_data.len() % 15 != 0 {
+        return Err(SbiParseError::Truncated);
+    }
+    let mut records = BTreeMap::new();
+    for record in lsd_data.chunks_exact(15) {
+        let msf = MsfIndex::from_bcd_values(record[0], record[1], record[2])?;
+        records.insert(msf.to_lba(), SbiRecord::Lsd(record[3..].try_into().unwrap()));
+    }
+
+    Ok(records)
+}
+
+// Picks the SBI record for a sector with the Q subchannel frame `actual`
+// instead of `expected`. SBI files can't store the CRC, so deviations in it
+// alone get a full record as well.
+fn sbi_record_for(actual: &[u8; 12], expected: &[u8; 12]) -> SbiRecord {
+    let differs = |range: std::ops::Range<usize>| actual[range.clone()] != expected[range];
+    if !differs(0..3) && !differs(6..10) && differs(3..6) {
+        SbiRecord::Relative(actual[3..6].try_into().unwrap())
+    } else if !differs(0..7) && differs(7..10) {
+        SbiRecord::Absolute(actual[7..10].try_into().unwrap())
+    } else {
+        SbiRecord::Full(actual[..10].try_into().unwrap())
+    }
+}
+
+/// Scans the Q subchannel of all sectors of `image` and returns records for
+/// the ones whose data or CRC differ from what is expected from their
+/// position. Frames of other modes than position (catalog number, ISRC)
+/// are only reported if their CRC is wrong. Only makes sense for images
+/// storing subchannel data, as it's synthesized otherwise. The subchannel
+/// data is read as stored in the image, an SBI or LSD file loaded alongside
+/// it is ignored.
+pub fn scan_image<I>(image: &mut I, format: PatchFormat) -> Result<SbiRecords, ImageError>
+    where I: Image + ?Sized
+{
+    let old_location = image.current_global_msf();
+
+    let mut records = BTreeMap::new();
+
+    image.set_location(MsfIndex::new(0,2,0).unwrap())?;
+
+    loop {
+        let mut interleaved = [0u8; 96];
+        image.copy_current_subchannel_unpatched(&mut interleaved)?;
+        let mut channels = [0u8; 96];
+        subchannel::deinterleave(&interleaved, &mut channels);
+        let actual: [u8; 12] = channels[12..24].try_into().unwrap();
+        let expected = subchannel::synthesize_q(image)?;
+        let crc_valid = u16::from_be_bytes([actual[10], actual[11]]) == subchannel::q_crc(&actual[..10]);
+        let is_position = actual[0] & 0x0f == 0x01;
+        if actual != expected && (is_position || !crc_valid) {
+            let record = match format {
+                PatchFormat::Sbi => sbi_record_for(&actual, &expected),
+                PatchFormat::Lsd => SbiRecord::Lsd(actual),
+            };
+            records.insert(image.current_global_msf()?.to_lba(), record);
         }
+        if image.advance_position()? == Some(Event::EndOfDisc) {
+            break;
+        }
+    }
+
+    if let Ok(loc) = old_location {
+        if let Err(e) = image.set_location(loc) {
+            error!("Failed to restore old location: {:?}", e);
+        }
+    }
+
+    Ok(records)
+}
+
+fn write_msf<W: Write>(writer: &mut W, lba: u32) -> io::Result<()> {
+    let msf = MsfIndex::from_lba(lba).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let (m, s, f) = msf.to_bcd_values();
+    writer.write_all(&[m, s, f])
+}
+
+/// Writes `records` as an SBI file. LSD records are stored as mode 1
+/// records, dropping their CRC.
+pub fn write_sbi<W: Write>(records: &SbiRecords, mut writer: W) -> io::Result<()> {
+    writer.write_all(b"SBI\0")?;
+    for (&lba, record) in records.iter() {
+        write_msf(&mut writer, lba)?;
+        writer.write_all(&[record.mode()])?;
+        let data = record.data();
+        writer.write_all(&data[..data.len().min(10)])?;
+    }
+    writer.flush()
+}
+
+/// Writes `records` as an LSD file. As LSD files store whole Q subchannel
+/// frames, mode 0, 2 and 3 records can't be written and result in an
+/// `InvalidInput` error. Mode 1 records get a CRC matching their data.
+pub fn write_lsd<W: Write>(records: &SbiRecords, mut writer: W) -> io::Result<()> {
+    for (&lba, record) in records.iter() {
+        let mut q = [0u8; 12];
+        match record {
+            SbiRecord::Full(_) | SbiRecord::Lsd(_) => record.apply(&mut q),
+            SbiRecord::Unpatched(_) | SbiRecord::Relative(_) | SbiRecord::Absolute(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    "LSD files can only store whole Q subchannel frames"));
+            }
+        }
+        write_msf(&mut writer, lba)?;
+        writer.write_all(&q)?;
     }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sbi_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sbi");
+        let mut sbi = b"SBI\0".to_vec();
+        sbi.extend([0x03, 0x08, 0x05, 0x01, 0x41, 0x01, 0x01, 0x07, 0x06, 0x05, 0x00, 0x23, 0x08, 0x05]);
+        sbi.extend([0x03, 0x08, 0x10, 0x03, 0x03, 0x08, 0x11]);
+        std::fs::write(&path, &sbi).unwrap();
+
+        let records = load_sbi_file(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        let lba = MsfIndex::new(3, 8, 5).unwrap().to_lba();
+        assert_eq!(records[&lba], SbiRecord::Full([0x41, 0x01, 0x01, 0x07, 0x06, 0x05, 0x00, 0x23, 0x08, 0x05]));
+        let lba = MsfIndex::new(3, 8, 10).unwrap().to_lba();
+        assert_eq!((records[&lba].mode(), records[&lba].data()), (3, &[0x03, 0x08, 0x11][..]));
+        let mut q = [0u8; 12];
+        records[&lba].apply(&mut q);
+        assert_eq!(&q[7..10], &[0x03, 0x08, 0x11]);
+        assert_eq!(u16::from_be_bytes([q[10], q[11]]), subchannel::q_crc(&q[..10]));
 
-    Ok(invalid_subq_lbas)
+        sbi.pop();
+        std::fs::write(&path, &sbi).unwrap();
+        assert!(matches!(load_sbi_file(&path), Err(SbiParseError::Truncated)));
+    }
+
+    #[test]
+    fn sbi_mode_0() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sbi");
+        let mut sbi = b"SBI\0".to_vec();
+        sbi.extend([0x03, 0x08, 0x05, 0x00, 0x01, 0x02, 0x03]);
+        sbi.extend([0x03, 0x08, 0x10, 0x02, 0x00, 0x00, 0x11]);
+        std::fs::write(&path, &sbi).unwrap();
+
+        let records = load_sbi_file(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        let record = &records[&MsfIndex::new(3, 8, 5).unwrap().to_lba()];
+        assert_eq!(record, &SbiRecord::Unpatched([0x01, 0x02, 0x03]));
+        // The frame is left as is, including its CRC
+        let mut q = [0x5a; 12];
+        record.apply(&mut q);
+        assert_eq!(q, [0x5a; 12]);
+
+        let mut written = Vec::new();
+        write_sbi(&records, &mut written).unwrap();
+        assert_eq!(written, sbi);
+        assert!(write_lsd(&records, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn lsd_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.lsd");
+        let q = [0x41, 0x01, 0x01, 0x07, 0x06, 0x05, 0x00, 0x23, 0x08, 0x05, 0x12, 0x34];
+        let lsd = [&[0x03, 0x08, 0x05][..], &q].concat();
+        std::fs::write(&path, &lsd).unwrap();
+
+        let records = load_lsd_file(&path).unwrap();
+        let record = &records[&MsfIndex::new(3, 8, 5).unwrap().to_lba()];
+        assert_eq!(record.mode(), 1);
+        // The stored CRC is kept
+        let mut frame = [0u8; 12];
+        record.apply(&mut frame);
+        assert_eq!(frame, q);
+
+        std::fs::write(&path, &lsd[1..]).unwrap();
+        assert!(matches!(load_lsd_file(&path), Err(SbiParseError::Truncated)));
+    }
+
+    #[test]
+    fn scan_and_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let cue_path = dir.path().join("test.cue");
+        std::fs::write(&cue_path, "FILE \"test.bin\" BINARY\n  TRACK 01 AUDIO\n    INDEX 01 00:00:00\n").unwrap();
+        std::fs::write(dir.path().join("test.bin"), [0u8; 4 * 2352]).unwrap();
+
+        // Deinterleaved subchannel data: the Q frame of the first sector is
+        // as expected, the second one has a modified absolute MSF, the third
+        // one a wrong CRC and the fourth one holds the catalog number.
+        let mut sub = [0u8; 4 * 96];
+        for (i, sector) in sub.chunks_exact_mut(96).enumerate() {
+            let q = &mut sector[12..24];
+            q[..10].copy_from_slice(&[0x01, 0x01, 0x01, 0x00, 0x00, i as u8, 0x00, 0x00, 0x02, i as u8]);
+            match i {
+                1 => q[9] = 0x11,
+                3 => q[..10].copy_from_slice(&[0x02, 0x01, 0x23, 0x45, 0x67, 0x89, 0x01, 0x23, 0x00, 0x03]),
+                _ => {}
+            }
+            let crc = subchannel::q_crc(&q[..10]);
+            q[10..12].copy_from_slice(&crc.to_be_bytes());
+            if i == 2 {
+                q[11] ^= 0xff;
+            }
+        }
+        std::fs::write(dir.path().join("test.sub"), sub).unwrap();
+
+        let mut cue = crate::cue::Cuesheet::open(&cue_path).unwrap();
+        let records = scan_image(&mut cue, PatchFormat::Sbi).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[&151], SbiRecord::Absolute([0x00, 0x02, 0x11]));
+        assert_eq!(records[&152], SbiRecord::Full(sub[2 * 96 + 12..2 * 96 + 22].try_into().unwrap()));
+
+        let sbi_path = dir.path().join("out.sbi");
+        write_sbi(&records, File::create(&sbi_path).unwrap()).unwrap();
+        assert_eq!(load_sbi_file(&sbi_path).unwrap(), records);
+
+        let records = scan_image(&mut cue, PatchFormat::Lsd).unwrap();
+        assert_eq!(records[&152], SbiRecord::Lsd(sub[2 * 96 + 12..2 * 96 + 24].try_into().unwrap()));
+        let lsd_path = dir.path().join("out.lsd");
+        write_lsd(&records, File::create(&lsd_path).unwrap()).unwrap();
+        assert_eq!(load_lsd_file(&lsd_path).unwrap(), records);
+
+        // An SBI file loaded alongside the image doesn't end up in the scan
+        let sidecar = BTreeMap::from([(150, SbiRecord::Absolute([0x00, 0x02, 0x42]))]);
+        write_sbi(&sidecar, File::create(dir.path().join("test.sbi")).unwrap()).unwrap();
+        let mut cue = crate::cue::Cuesheet::open(&cue_path).unwrap();
+        assert_eq!(cue.sbi_records(), Some(&sidecar));
+        assert_eq!(cue.current_subchannel_q().unwrap()[9], 0x42);
+        let records = scan_image(&mut cue, PatchFormat::Sbi).unwrap();
+        assert_eq!(records.keys().collect::<Vec<_>>(), [&151, &152]);
+    }
 }
\ No newline at end of file
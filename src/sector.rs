@@ -0,0 +1,199 @@
+// Conversion of the "cooked" sector formats found in some images (user data
+// only, without sync pattern, header and error correction) into raw 2352 byte
+// sectors as they would be read from a disc.
+
+use crate::MsfIndex;
+
+
+const SYNC_PATTERN: [u8; 12] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+
+// Subheader used when synthesizing Mode 2 Form 1 sectors
+const FORM1_SUBHEADER: [u8; 8] = [0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x08, 0x00];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SectorFormat {
+    // Complete 2352 byte sectors
+    Raw,
+    // 2048 bytes of Mode 1 user data
+    Mode1Cooked,
+    // 2336 bytes of Mode 2 data, i.e. everything following the header
+    Mode2Cooked,
+    // 2048 bytes of Mode 2 Form 1 user data
+    Mode2Form1Cooked,
+}
+
+impl SectorFormat {
+    // Number of bytes of a sector of this format stored in the image
+    pub fn data_size(self) -> usize {
+        match self {
+            SectorFormat::Raw => 2352,
+            SectorFormat::Mode1Cooked | SectorFormat::Mode2Form1Cooked => 2048,
+            SectorFormat::Mode2Cooked => 2336,
+        }
+    }
+
+    // Builds the raw sector at absolute position `lba` from `data`, which has
+    // to be `data_size()` bytes long. `buf` needs to be 2352 bytes long.
+    pub fn expand(self, lba: u32, data: &[u8], buf: &mut [u8]) {
+        match self {
+            SectorFormat::Raw => buf.copy_from_slice(data),
+            SectorFormat::Mode1Cooked => {
+                write_header(lba, 1, buf);
+                buf[0x10..0x810].copy_from_slice(data);
+                let edc = edc(&buf[..0x810]);
+                buf[0x810..0x814].copy_from_slice(&edc.to_le_bytes());
+                buf[0x814..0x81c].fill(0);
+                write_ecc(buf);
+            }
+            SectorFormat::Mode2Cooked => {
+                write_header(lba, 2, buf);
+                buf[0x10..].copy_from_slice(data);
+            }
+            SectorFormat::Mode2Form1Cooked => {
+                write_header(lba, 2, buf);
+                buf[0x10..0x18].copy_from_slice(&FORM1_SUBHEADER);
+                buf[0x18..0x818].copy_from_slice(data);
+                let edc = edc(&buf[0x10..0x818]);
+                buf[0x818..0x81c].copy_from_slice(&edc.to_le_bytes());
+                // The header isn't covered by the ECC of Mode 2 sectors
+                let mut header = [0u8; 4];
+                header.copy_from_slice(&buf[0x0c..0x10]);
+                buf[0x0c..0x10].fill(0);
+                write_ecc(buf);
+                buf[0x0c..0x10].copy_from_slice(&header);
+            }
+        }
+    }
+}
+
+fn write_header(lba: u32, mode: u8, buf: &mut [u8]) {
+    buf[..12].copy_from_slice(&SYNC_PATTERN);
+    let (m, s, f) = MsfIndex::from_lba(lba)
+        .map(|x| x.to_bcd_values())
+        .unwrap_or((0, 0, 0));
+    buf[12] = m;
+    buf[13] = s;
+    buf[14] = f;
+    buf[15] = mode;
+}
+
+const fn edc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut edc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            edc = (edc >> 1) ^ if edc & 1 != 0 { 0xd801_8001 } else { 0 };
+            bit += 1;
+        }
+        table[i] = edc;
+        i += 1;
+    }
+    table
+}
+
+static EDC_TABLE: [u32; 256] = edc_table();
+
+pub(crate) fn edc(data: &[u8]) -> u32 {
+    data.iter().fold(0, |edc, &x| (edc >> 8) ^ EDC_TABLE[((edc ^ x as u32) & 0xff) as usize])
+}
+
+// Lookup tables for multiplication by 2 in GF(2^8) and for dividing by 3
+const fn ecc_tables() -> ([u8; 256], [u8; 256]) {
+    let mut f_lut = [0u8; 256];
+    let mut b_lut = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        let j = (i << 1) ^ if i & 0x80 != 0 { 0x11d } else { 0 };
+        f_lut[i] = j as u8;
+        b_lut[i ^ j] = i as u8;
+        i += 1;
+    }
+    (f_lut, b_lut)
+}
+
+static ECC_TABLES: ([u8; 256], [u8; 256]) = ecc_tables();
+
+// Computes one set of Reed-Solomon product code parity bytes over the sector,
+// starting at the header.
+fn ecc_compute(sector: &mut [u8], major_count: usize, minor_count: usize,
+               major_mult: usize, minor_inc: usize, dest: usize)
+{
+    let (f_lut, b_lut) = &ECC_TABLES;
+    let size = major_count * minor_count;
+    for major in 0..major_count {
+        let mut index = (major >> 1) * major_mult + (major & 1);
+        let mut ecc_a = 0u8;
+        let mut ecc_b = 0u8;
+        for _ in 0..minor_count {
+            let x = sector[0x0c + index];
+            index += minor_inc;
+            if index >= size {
+                index -= size;
+            }
+            ecc_a ^= x;
+            ecc_b ^= x;
+            ecc_a = f_lut[ecc_a as usize];
+        }
+        ecc_a = b_lut[(f_lut[ecc_a as usize] ^ ecc_b) as usize];
+        sector[dest + major] = ecc_a;
+        sector[dest + major + major_count] = ecc_a ^ ecc_b;
+    }
+}
+
+fn write_ecc(sector: &mut [u8]) {
+    // P parity
+    ecc_compute(sector, 86, 24, 2, 86, 0x81c);
+    // Q parity
+    ecc_compute(sector, 52, 43, 86, 88, 0x8c8);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sector::*;
+
+    // EDC, the 8 zero bytes and P/Q parity of the sector built in
+    // `mode1_sector`, as generated by the ECC code of MAME's cdrom.c
+    const MODE1_SECTOR_TAIL: [u8; 288] = [
+        0x5e, 0x93, 0x51, 0x92, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x42, 0x13, 0x28, 0x94, 0x21, 0x72, 0xd5, 0x86, 0x59, 0x0a, 0x8a, 0xd9,
+        0xf5, 0xa6, 0xab, 0xf8, 0x83, 0xd0, 0x8f, 0xdc, 0xa6, 0xf5, 0xe4, 0xb7,
+        0x8c, 0xdf, 0x51, 0x02, 0x63, 0x30, 0x52, 0x01, 0xe9, 0xba, 0x4e, 0x1d,
+        0x11, 0x42, 0x88, 0xdb, 0x31, 0x62, 0xed, 0xbe, 0x4d, 0x1e, 0x02, 0x51,
+        0x62, 0x31, 0xc9, 0x9a, 0x6c, 0x3f, 0x00, 0x53, 0x05, 0x56, 0xdc, 0x8f,
+        0x13, 0x40, 0x44, 0x17, 0x4f, 0x1c, 0xa6, 0xf5, 0x14, 0x47, 0xe0, 0xb3,
+        0x6c, 0x3f, 0xc0, 0xda, 0x93, 0x9b, 0x56, 0x06, 0xe8, 0xb8, 0xa5, 0xf5,
+        0x8f, 0xdf, 0x8e, 0xdc, 0x20, 0x8a, 0x11, 0x42, 0xd5, 0x86, 0x69, 0x3a,
+        0xea, 0xb9, 0xc5, 0x96, 0xcb, 0x98, 0x93, 0xc0, 0xef, 0xbc, 0xb6, 0xe5,
+        0xa4, 0xf7, 0x9c, 0xcf, 0x71, 0x22, 0x73, 0x20, 0x72, 0x21, 0x19, 0x4a,
+        0x6e, 0x3d, 0xe1, 0xb2, 0x88, 0xdb, 0xc1, 0x92, 0xcd, 0x9e, 0xbd, 0xee,
+        0x22, 0x71, 0x72, 0x21, 0xe9, 0xba, 0x7c, 0x2f, 0x40, 0x13, 0x15, 0x46,
+        0xbc, 0xef, 0x03, 0x50, 0x24, 0x77, 0x7f, 0x2c, 0xc6, 0x95, 0x24, 0x77,
+        0xe0, 0xb3, 0x5c, 0x0f, 0x7e, 0xa8, 0xf0, 0x3a, 0xb2, 0xe3, 0xfe, 0xaf,
+        0x4d, 0x1c, 0x95, 0xc4, 0x46, 0xe9, 0x44, 0x86, 0xf1, 0x2a, 0xe2, 0x0b,
+        0x9d, 0x10, 0x46, 0x03, 0x7a, 0xb2, 0xdf, 0x13, 0x1b, 0x2e, 0x5e, 0xd6,
+        0x97, 0xd8, 0xd6, 0xc6, 0x6c, 0x44, 0xf5, 0x89, 0xca, 0x58, 0x26, 0x9e,
+        0x81, 0xae, 0x77, 0x6b, 0xea, 0x90, 0xff, 0x49, 0x67, 0x54, 0x52, 0x76,
+        0x54, 0x5e, 0x1a, 0x11, 0xdb, 0xd4, 0x5d, 0xb4, 0x81, 0x2c, 0xe6, 0x25,
+        0x42, 0x98, 0x2c, 0xc4, 0x09, 0x85, 0x05, 0x41, 0xf7, 0x3e, 0xec, 0x20,
+        0xdb, 0xbf, 0x09, 0x80, 0x5f, 0x11, 0xe9, 0x3b, 0x5a, 0xe6, 0x1f, 0xb2,
+        0x8a, 0x9c, 0xdd, 0x64, 0x35, 0x1b, 0xd7, 0xca, 0xf5, 0x8e, 0x9d, 0x2a,
+        0x07, 0x35, 0xa3, 0x86, 0xd9, 0xd2, 0x98, 0x7d, 0x89, 0x7c, 0x8e, 0x23,
+    ];
+
+    #[test]
+    fn mode1_sector() {
+        let data: Vec<u8> = (0..2048).map(|x| x as u8).collect();
+        let mut buf = [0u8; 2352];
+        SectorFormat::Mode1Cooked.expand(16 + 150, &data, &mut buf);
+
+        assert_eq!(&buf[..12], &SYNC_PATTERN);
+        assert_eq!(&buf[12..16], &[0x00, 0x02, 0x16, 0x01]);
+        assert_eq!(&buf[16..2064], &data[..]);
+        // The EDC is a CRC, so running it over the data including the
+        // checksum has to yield zero.
+        assert_eq!(edc(&buf[..0x814]), 0);
+        assert_eq!(&buf[0x810..], &MODE1_SECTOR_TAIL[..]);
+    }
+}
@@ -0,0 +1,121 @@
+// Helpers for the P-W subchannel data: conversion between the interleaved
+// (one bit of each channel per byte, P in the most significant bit) and the
+// deinterleaved (12 bytes per channel) layouts, and synthesis of the data for
+// images that don't store any.
+
+use crate::sbi::SbiRecord;
+use crate::{Image, ImageError, MsfIndex};
+
+
+pub(crate) fn interleave(src: &[u8; 96], dst: &mut [u8; 96]) {
+    for (i, byte) in dst.iter_mut().enumerate() {
+        *byte = (0..8).fold(0, |acc, channel| {
+            let bit = (src[channel * 12 + i / 8] >> (7 - i % 8)) & 1;
+            acc | (bit << (7 - channel))
+        });
+    }
+}
+
+pub(crate) fn deinterleave(src: &[u8; 96], dst: &mut [u8; 96]) {
+    dst.fill(0);
+    for (i, &byte) in src.iter().enumerate() {
+        for channel in 0..8 {
+            let bit = (byte >> (7 - channel)) & 1;
+            dst[channel * 12 + i / 8] |= bit << (7 - i % 8);
+        }
+    }
+}
+
+// CRC-16/CCITT over the first 10 bytes of a Q subchannel frame, stored
+// inverted in the last two
+pub(crate) fn q_crc(data: &[u8]) -> u16 {
+    let crc = data.iter().fold(0u16, |mut crc, &x| {
+        crc ^= (x as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+        crc
+    });
+    !crc
+}
+
+fn bcd(x: u8) -> u8 {
+    ((x / 10) << 4) | (x % 10)
+}
+
+// Builds the mode 1 (position) Q subchannel frame of the current sector
+pub(crate) fn synthesize_q<I: Image + ?Sized>(image: &I) -> Result<[u8; 12], ImageError> {
+    let track = image.current_track()?;
+    let absolute = image.current_global_msf()?;
+    // Counts down to index 01 in the pregap
+    let relative = MsfIndex::from_lba(absolute.to_lba().abs_diff(image.track_start(track)?.to_lba()))?;
+    let control = image.current_track_flags()?.control(image.current_track_type()?);
+
+    let mut q = [0u8; 12];
+    q[0] = (control << 4) | 0x01;
+    q[1] = bcd(track);
+    q[2] = bcd(image.current_index()?);
+    let (m, s, f) = relative.to_bcd_values();
+    q[3..6].copy_from_slice(&[m, s, f]);
+    let (m, s, f) = absolute.to_bcd_values();
+    q[7..10].copy_from_slice(&[m, s, f]);
+    let crc = q_crc(&q[..10]);
+    q[10..12].copy_from_slice(&crc.to_be_bytes());
+    Ok(q)
+}
+
+// Synthesizes the interleaved subchannel data of the current sector: P set
+// in pregaps, Q holding the position and R-W empty.
+pub(crate) fn synthesize<I: Image + ?Sized>(image: &I, buf: &mut [u8; 96]) -> Result<(), ImageError> {
+    let mut channels = [0u8; 96];
+    if image.current_index()? == 0 {
+        channels[..12].fill(0xff);
+    }
+    channels[12..24].copy_from_slice(&synthesize_q(image)?);
+    interleave(&channels, buf);
+    Ok(())
+}
+
+// Replaces R-W in the interleaved data in `buf` with the ones from `stored`,
+// keeping P and Q. For formats that only store R-W, like CD+G tracks.
+pub(crate) fn merge_rw(buf: &mut [u8; 96], stored: &[u8; 96]) {
+    for (x, &y) in buf.iter_mut().zip(stored) {
+        *x = (*x & 0xc0) | (y & 0x3f);
+    }
+}
+
+// Replaces the Q subchannel in the interleaved data in `buf` with the one
+// from an SBI file
+pub(crate) fn apply_sbi_record(buf: &mut [u8; 96], record: &SbiRecord) {
+    let mut channels = [0u8; 96];
+    deinterleave(buf, &mut channels);
+    record.apply(&mut channels[12..24]);
+    interleave(&channels, buf);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interleaving() {
+        let mut channels = [0u8; 96];
+        // P set for the first sample only, all of W set
+        channels[0] = 0x80;
+        channels[84..].fill(0xff);
+        let mut interleaved = [0u8; 96];
+        interleave(&channels, &mut interleaved);
+        assert_eq!(interleaved[0], 0x81);
+        assert!(interleaved[1..].iter().all(|&x| x == 0x01));
+
+        let mut deinterleaved = [0u8; 96];
+        deinterleave(&interleaved, &mut deinterleaved);
+        assert_eq!(deinterleaved, channels);
+    }
+
+    #[test]
+    fn q_crc_value() {
+        // Check value of CRC-16/XMODEM
+        assert_eq!(q_crc(b"123456789"), !0x31c3);
+    }
+}
@@ -0,0 +1,160 @@
+// Format independent description of the disc layout as returned by
+// `Image::toc`.
+
+use std::ops::{BitOr, BitOrAssign};
+
+#[cfg(feature = "serde-support")]
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{MsfIndex, MsfIndexError, TrackType};
+
+
+// Control field of the Q subchannel: data track
+const CONTROL_DATA: u8 = 0x04;
+
+/// Flags of a track as stored in the control field of its Q subchannel
+/// (apart from the data track bit, which follows from the track type) and
+/// the SCMS flag of cuesheets.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde-support", derive(Serialize, Deserialize))]
+pub struct TrackFlags(u8);
+
+impl TrackFlags {
+    /// Audio with pre-emphasis (PRE)
+    pub const PRE_EMPHASIS: TrackFlags = TrackFlags(0x01);
+    /// Digital copy permitted (DCP)
+    pub const COPY_PERMITTED: TrackFlags = TrackFlags(0x02);
+    /// Four channel audio (4CH)
+    pub const FOUR_CHANNEL: TrackFlags = TrackFlags(0x08);
+    /// Serial copy management system (SCMS), not part of the control field
+    pub const SCMS: TrackFlags = TrackFlags(0x80);
+
+    pub const fn empty() -> TrackFlags {
+        TrackFlags(0)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(self, other: TrackFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    // Takes the flags from the control field of a Q subchannel frame
+    pub(crate) fn from_control(control: u8) -> TrackFlags {
+        TrackFlags(control & 0x0b)
+    }
+
+    /// Returns the control field of the Q subchannel of a track of type
+    /// `track_type` with these flags.
+    pub fn control(self, track_type: TrackType) -> u8 {
+        let data = match track_type {
+            TrackType::Audio => 0,
+            TrackType::Mode1 | TrackType::Mode2 => CONTROL_DATA,
+        };
+        (self.0 & 0x0b) | data
+    }
+}
+
+impl BitOr for TrackFlags {
+    type Output = TrackFlags;
+
+    fn bitor(self, rhs: TrackFlags) -> TrackFlags {
+        TrackFlags(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for TrackFlags {
+    fn bitor_assign(&mut self, rhs: TrackFlags) {
+        self.0 |= rhs.0;
+    }
+}
+
+/// Table of contents of a disc.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde-support", derive(Serialize, Deserialize))]
+pub struct Toc {
+    pub sessions: Vec<Session>,
+    pub first_track: u8,
+    pub last_track: u8,
+    /// Start of the lead-out of the last session, i.e. the end of the disc
+    pub lead_out: MsfIndex,
+    pub tracks: Vec<TrackInfo>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde-support", derive(Serialize, Deserialize))]
+pub struct Session {
+    pub number: u8,
+    pub first_track: u8,
+    pub last_track: u8,
+    /// Start of the lead-out of the session, i.e. the end of its last track
+    pub lead_out: MsfIndex,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde-support", derive(Serialize, Deserialize))]
+pub struct TrackInfo {
+    pub number: u8,
+    pub session: u8,
+    pub track_type: TrackType,
+    pub flags: TrackFlags,
+    /// Control field of the Q subchannel (0x04: data track), see
+    /// `TrackFlags::control`
+    pub control: u8,
+    /// Position of index 01
+    pub start: MsfIndex,
+    /// Number of sectors from index 01 up to the next track (or the
+    /// lead-out), including the postgap
+    pub length: u32,
+    /// Positions of all indices of the track, including index 00 if the track
+    /// has a pregap
+    pub indices: Vec<(u8, MsfIndex)>,
+    /// Number of sectors before index 01
+    pub pregap: u32,
+    /// Number of sectors at the end of the track that aren't part of the
+    /// image data (only known for some formats)
+    pub postgap: u32,
+}
+
+impl Toc {
+    // Groups `tracks`, which have to be sorted and non-empty, into sessions.
+    // `lead_outs` holds the absolute LBA of the lead-out of every session in
+    // order, the last one being the end of the disc.
+    pub(crate) fn from_tracks(tracks: Vec<TrackInfo>, lead_outs: &[u32]) -> Result<Toc, MsfIndexError> {
+        let mut sessions: Vec<Session> = Vec::new();
+        for track in tracks.iter() {
+            match sessions.last_mut() {
+                Some(session) if session.number == track.session => {
+                    session.last_track = track.number;
+                }
+                _ => {
+                    let lead_out = lead_outs[sessions.len()];
+                    sessions.push(Session {
+                        number: track.session,
+                        first_track: track.number,
+                        last_track: track.number,
+                        lead_out: MsfIndex::from_lba(lead_out)?,
+                    });
+                }
+            }
+        }
+        Ok(Toc {
+            sessions,
+            first_track: tracks.first().unwrap().number,
+            last_track: tracks.last().unwrap().number,
+            lead_out: MsfIndex::from_lba(*lead_outs.last().unwrap())?,
+            tracks,
+        })
+    }
+
+    /// Returns the track with the given number.
+    pub fn track(&self, number: u8) -> Option<&TrackInfo> {
+        self.tracks.iter().find(|x| x.number == number)
+    }
+}